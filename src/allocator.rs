@@ -0,0 +1,222 @@
+use crate::backplane::{node_of, on_node, NodeId, NODE_BITS};
+
+/// Number of low bits of a room id that hold the slot index,
+/// the bits above it up to the node bits hold the generation
+const INDEX_BITS: u32 = usize::BITS / 2;
+
+const INDEX_MASK: usize = (1 << INDEX_BITS) - 1;
+
+const GENERATION_MASK: usize = usize::MAX >> (INDEX_BITS + NODE_BITS);
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Slot {
+    generation: usize,
+    live: bool,
+}
+
+/// Hands out room ids from a fixed number of slots.
+///
+/// Every id carries the generation of its slot, so once a room is refunded
+/// its old id never matches the room that reuses the slot. Ids also carry the node
+/// that hands them out, so rooms of different nodes never share an id.
+#[derive(Debug)]
+pub struct RoomAllocator {
+    slots: Vec<Slot>,
+    free: Vec<usize>,
+    node: NodeId,
+}
+
+impl RoomAllocator {
+    pub fn new(capacity: usize, node: NodeId) -> RoomAllocator {
+        let capacity = capacity.min(INDEX_MASK + 1);
+
+        RoomAllocator {
+            slots: vec![Slot::default(); capacity],
+            // reversed so that the lowest slots are handed out first
+            free: (0..capacity).rev().collect(),
+            node,
+        }
+    }
+}
+
+impl RoomAllocator {
+    /// Reserve a free room, `None` when every slot is taken
+    pub fn reserve(&mut self) -> Option<usize> {
+        let index = self.free.pop()?;
+        let slot = &mut self.slots[index];
+        slot.live = true;
+
+        Some(on_node(encode(index, slot.generation), self.node))
+    }
+
+    /// Give a room back. Refunding an id that is not live is a no-op and returns `false`.
+    pub fn refund(&mut self, id: usize) -> bool {
+        let (index, generation) = decode(id);
+
+        match self.slots.get_mut(index) {
+            Some(slot)
+                if slot.live && slot.generation == generation && node_of(id) == self.node =>
+            {
+                slot.live = false;
+                slot.generation = (slot.generation + 1) & GENERATION_MASK;
+                self.free.push(index);
+                true
+            }
+            _ => {
+                log::warn!("ignoring refund of room {} that is not reserved", id);
+                false
+            }
+        }
+    }
+
+    /// Reserve exactly `id`, e.g. a room restored from a snapshot.
+    /// Returns `false` when its slot is out of range or already taken.
+    pub fn claim(&mut self, id: usize) -> bool {
+        let (index, generation) = decode(id);
+
+        match self.slots.get_mut(index) {
+            Some(slot) if !slot.live && node_of(id) == self.node => {
+                slot.live = true;
+                slot.generation = generation;
+                self.free.retain(|free| *free != index);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether `id` is the current, reserved id of its slot
+    pub fn is_live(&self, id: usize) -> bool {
+        let (index, generation) = decode(id);
+
+        self.slots.get(index).is_some_and(|slot| {
+            slot.live && slot.generation == generation && node_of(id) == self.node
+        })
+    }
+}
+
+fn encode(index: usize, generation: usize) -> usize {
+    (generation << INDEX_BITS) | index
+}
+
+fn decode(id: usize) -> (usize, usize) {
+    (id & INDEX_MASK, (id >> INDEX_BITS) & GENERATION_MASK)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use proptest::prelude::*;
+
+    use super::*;
+
+    #[derive(Debug, Clone)]
+    enum Op {
+        Reserve,
+        /// Refund one of the ids handed out so far, picked by index
+        Refund(usize),
+        /// Refund an id that was never handed out
+        RefundUnknown(usize),
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            3 => Just(Op::Reserve),
+            3 => any::<usize>().prop_map(Op::Refund),
+            1 => any::<usize>().prop_map(Op::RefundUnknown),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn random_reserve_refund_sequences(capacity in 0usize..16, ops in prop::collection::vec(op(), 0..200)) {
+            let mut allocator = RoomAllocator::new(capacity, 0);
+            let mut live = HashSet::new();
+            let mut issued = Vec::new();
+
+            for op in ops {
+                match op {
+                    Op::Reserve => match allocator.reserve() {
+                        Some(id) => {
+                            prop_assert!(live.len() < capacity);
+                            prop_assert!(!issued.contains(&id), "id {} was handed out twice", id);
+                            prop_assert!(allocator.is_live(id));
+                            live.insert(id);
+                            issued.push(id);
+                        }
+                        None => prop_assert_eq!(live.len(), capacity),
+                    },
+                    Op::Refund(pick) => {
+                        if issued.is_empty() {
+                            continue;
+                        }
+                        let id = issued[pick % issued.len()];
+                        prop_assert_eq!(allocator.refund(id), live.remove(&id));
+                        prop_assert!(!allocator.is_live(id));
+                    }
+                    Op::RefundUnknown(id) => {
+                        if issued.contains(&id) {
+                            continue;
+                        }
+                        prop_assert!(!allocator.refund(id));
+                    }
+                }
+            }
+
+            for id in &issued {
+                prop_assert_eq!(allocator.is_live(*id), live.contains(id));
+            }
+        }
+    }
+
+    #[test]
+    fn double_refund_is_noop() {
+        let mut allocator = RoomAllocator::new(2, 0);
+        let id = allocator.reserve().unwrap();
+
+        assert!(allocator.refund(id));
+        assert!(!allocator.refund(id));
+        assert_eq!(allocator.free.len(), 2);
+    }
+
+    #[test]
+    fn claimed_id_is_not_handed_out_again() {
+        let mut allocator = RoomAllocator::new(2, 0);
+        let claimed = encode(1, 7);
+
+        assert!(allocator.claim(claimed));
+        assert!(!allocator.claim(claimed));
+        assert!(!allocator.claim(encode(2, 0)));
+        assert!(allocator.is_live(claimed));
+
+        let other = allocator.reserve().unwrap();
+        assert_eq!(decode(other).0, 0);
+        assert_eq!(allocator.reserve(), None);
+    }
+
+    #[test]
+    fn ids_of_other_nodes_are_not_live() {
+        let mut allocator = RoomAllocator::new(1, 2);
+        let id = allocator.reserve().unwrap();
+        assert_eq!(node_of(id), 2);
+
+        let other = on_node(id, 3);
+        assert!(!allocator.is_live(other));
+        assert!(!allocator.refund(other));
+        assert!(allocator.refund(id));
+    }
+
+    #[test]
+    fn stale_id_does_not_match_reused_slot() {
+        let mut allocator = RoomAllocator::new(1, 0);
+        let old = allocator.reserve().unwrap();
+        allocator.refund(old);
+
+        let new = allocator.reserve().unwrap();
+        assert_ne!(old, new);
+        assert!(!allocator.is_live(old));
+        assert!(!allocator.refund(old));
+        assert!(allocator.is_live(new));
+    }
+}
@@ -0,0 +1,343 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::io;
+use std::sync::{mpsc, Arc, PoisonError, RwLock};
+use std::time::Duration;
+
+use actix::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::server::{Reply, Request};
+
+/// Number of a server process among the ones sharing rooms
+pub type NodeId = u8;
+
+/// Top bits of session and room ids that hold the node they live on
+pub const NODE_BITS: u32 = 8;
+
+const NODE_SHIFT: u32 = usize::BITS - NODE_BITS;
+
+/// Node a session or room id belongs to
+pub fn node_of(id: usize) -> NodeId {
+    (id >> NODE_SHIFT) as NodeId
+}
+
+/// `id` with its node bits set to `node`
+pub fn on_node(id: usize, node: NodeId) -> usize {
+    (id & (usize::MAX >> NODE_BITS)) | (usize::from(node) << NODE_SHIFT)
+}
+
+/// What nodes send each other
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Envelope {
+    /// Text for a session of the receiving node. An acknowledged direct message
+    /// carries its id and the node the `/ack` goes back to.
+    Deliver {
+        session: usize,
+        line: String,
+        ack: Option<(u64, NodeId)>,
+    },
+    /// A session of the receiving node joined or left a room of the sending node
+    Membership {
+        session: usize,
+        room: usize,
+        member: bool,
+    },
+    /// A command for a room of the receiving node, answered with a `Reply`
+    /// carrying the same correlation id unless it is `0`
+    Request {
+        from: NodeId,
+        correlation: u64,
+        request: Request,
+    },
+    Reply {
+        correlation: u64,
+        reply: Reply,
+    },
+}
+
+/// An envelope another node sent to this one
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Remote(pub Envelope);
+
+/// Carries envelopes between the chat servers of all nodes
+pub trait Backplane: Debug + Send {
+    fn node(&self) -> NodeId;
+    /// Start handing envelopes sent to this node to `recipient`
+    fn subscribe(&mut self, recipient: Recipient<Remote>) -> io::Result<()>;
+    /// Envelopes to nodes that are gone are lost
+    fn send(&self, node: NodeId, envelope: Envelope);
+}
+
+/// Chat servers of one process, e.g. the shards of a node
+#[derive(Debug, Clone, Default)]
+pub struct Hub {
+    /// only written while the chat servers start, every envelope reads it
+    nodes: Arc<RwLock<HashMap<NodeId, Recipient<Remote>>>>,
+}
+
+impl Hub {
+    pub fn node(&self, node: NodeId) -> InMemory {
+        InMemory {
+            node,
+            hub: self.clone(),
+            remote: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct InMemory {
+    node: NodeId,
+    hub: Hub,
+    /// reaches the nodes of other processes
+    remote: Option<Box<dyn Backplane>>,
+}
+
+impl InMemory {
+    /// Send envelopes for nodes outside the hub through `remote`
+    pub fn or(self, remote: impl Backplane + 'static) -> InMemory {
+        InMemory {
+            remote: Some(Box::new(remote)),
+            ..self
+        }
+    }
+}
+
+impl Backplane for InMemory {
+    fn node(&self) -> NodeId {
+        self.node
+    }
+
+    fn subscribe(&mut self, recipient: Recipient<Remote>) -> io::Result<()> {
+        self.hub
+            .nodes
+            .write()
+            .unwrap_or_else(poisoned)
+            .insert(self.node, recipient.clone());
+        match &mut self.remote {
+            Some(remote) => remote.subscribe(recipient),
+            None => Ok(()),
+        }
+    }
+
+    fn send(&self, node: NodeId, envelope: Envelope) {
+        let recipient = self
+            .hub
+            .nodes
+            .read()
+            .unwrap_or_else(poisoned)
+            .get(&node)
+            .cloned();
+
+        match (recipient, &self.remote) {
+            (Some(recipient), _) => recipient.do_send(Remote(envelope)),
+            (None, Some(remote)) => remote.send(node, envelope),
+            (None, None) => log::warn!("no node {} to send to", node),
+        }
+    }
+}
+
+/// A shard panicked while holding the node map, which it leaves intact
+fn poisoned<T>(err: PoisonError<T>) -> T {
+    log::error!("node map lock poisoned, using it anyway");
+    err.into_inner()
+}
+
+/// Nodes in separate processes talking over Redis pub/sub, every node listens on its own channel
+#[derive(Debug)]
+pub struct Redis {
+    node: NodeId,
+    client: redis::Client,
+    /// Envelopes waiting for the publishing thread, as channel and JSON
+    outgoing: mpsc::Sender<(String, String)>,
+}
+
+impl Redis {
+    /// Check that the server at `url` answers and start publishing to it
+    pub fn connect(url: &str, node: NodeId) -> io::Result<Redis> {
+        let client = redis::Client::open(url).map_err(io::Error::other)?;
+        let mut connection = client.get_connection().map_err(io::Error::other)?;
+
+        let (outgoing, queued) = mpsc::channel::<(String, String)>();
+        std::thread::spawn({
+            let client = client.clone();
+            move || {
+                for (channel, json) in queued {
+                    let published = redis::cmd("PUBLISH")
+                        .arg(&channel)
+                        .arg(&json)
+                        .query::<()>(&mut connection);
+                    if let Err(err) = published {
+                        log::error!("cant publish to {}: {}", channel, err);
+                        if let Ok(fresh) = client.get_connection() {
+                            connection = fresh;
+                        }
+                    }
+                }
+            }
+        });
+
+        Ok(Redis {
+            node,
+            client,
+            outgoing,
+        })
+    }
+}
+
+impl Backplane for Redis {
+    fn node(&self) -> NodeId {
+        self.node
+    }
+
+    fn subscribe(&mut self, recipient: Recipient<Remote>) -> io::Result<()> {
+        let client = self.client.clone();
+        let channel = channel(self.node);
+
+        std::thread::spawn(move || loop {
+            if let Err(err) = listen(&client, &channel, &recipient) {
+                log::error!("lost subscription to {}: {}", channel, err);
+                std::thread::sleep(Duration::from_secs(1));
+            }
+        });
+
+        Ok(())
+    }
+
+    fn send(&self, node: NodeId, envelope: Envelope) {
+        match serde_json::to_string(&envelope) {
+            Ok(json) => {
+                let _ = self.outgoing.send((channel(node), json));
+            }
+            Err(err) => log::error!("cant encode envelope for node {}: {}", node, err),
+        }
+    }
+}
+
+fn channel(node: NodeId) -> String {
+    format!("peershare:node:{}", node)
+}
+
+fn listen(
+    client: &redis::Client,
+    channel: &str,
+    recipient: &Recipient<Remote>,
+) -> redis::RedisResult<()> {
+    let mut connection = client.get_connection()?;
+    let mut pubsub = connection.as_pubsub();
+    pubsub.subscribe(channel)?;
+
+    loop {
+        let payload: String = pubsub.get_message()?.get_payload()?;
+        match serde_json::from_str(&payload) {
+            Ok(envelope) => recipient.do_send(Remote(envelope)),
+            Err(err) => log::warn!("dropping bad envelope on {}: {}", channel, err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use actix::prelude::*;
+
+    use super::{node_of, on_node, Backplane, Envelope, NodeId, Redis, Remote};
+    use crate::server::{Reply, Request};
+
+    /// Keeps what a node received
+    struct Inbox(Arc<Mutex<Vec<Envelope>>>);
+
+    impl Actor for Inbox {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<Remote> for Inbox {
+        type Result = ();
+
+        fn handle(&mut self, msg: Remote, _: &mut Context<Self>) {
+            self.0.lock().unwrap().push(msg.0);
+        }
+    }
+
+    fn subscribed(node: NodeId, url: &str) -> (Redis, Arc<Mutex<Vec<Envelope>>>) {
+        let mut redis = Redis::connect(url, node)
+            .unwrap_or_else(|err| panic!("no redis at {}: {}", url, err));
+        let received = Arc::new(Mutex::new(Vec::new()));
+        redis
+            .subscribe(Inbox(received.clone()).start().recipient())
+            .unwrap();
+        (redis, received)
+    }
+
+    /// Send until the envelope arrives, the subscription starts in the background
+    async fn deliver(
+        from: &Redis,
+        node: NodeId,
+        received: &Mutex<Vec<Envelope>>,
+        envelope: impl Fn() -> Envelope,
+    ) -> Envelope {
+        for _ in 0..50 {
+            from.send(node, envelope());
+            actix::clock::sleep(Duration::from_millis(100)).await;
+            if let Some(envelope) = received.lock().unwrap().pop() {
+                return envelope;
+            }
+        }
+        panic!("node {} got nothing", node);
+    }
+
+    #[test]
+    fn node_is_kept_in_the_top_bits() {
+        let id = on_node(usize::MAX, 3);
+        assert_eq!(node_of(id), 3);
+        assert_eq!(
+            id & (usize::MAX >> super::NODE_BITS),
+            usize::MAX >> super::NODE_BITS
+        );
+
+        assert_eq!(node_of(on_node(42, 0)), 0);
+        assert_eq!(on_node(on_node(42, 7), 0), 42);
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs a Redis server at PEERSHARE_TEST_REDIS or on localhost"]
+    async fn envelopes_go_both_ways_over_redis() {
+        let url = std::env::var("PEERSHARE_TEST_REDIS")
+            .unwrap_or_else(|_| "redis://127.0.0.1/".to_owned());
+        let (one, to_one) = subscribed(1, &url);
+        let (two, to_two) = subscribed(2, &url);
+
+        let request = deliver(&one, 2, &to_two, || Envelope::Request {
+            from: 1,
+            correlation: 7,
+            request: Request::Drop(on_node(5, 2)),
+        })
+        .await;
+        let Envelope::Request {
+            from,
+            correlation,
+            request: Request::Drop(id),
+        } = request
+        else {
+            panic!("unexpected {:?}", request);
+        };
+        assert_eq!((from, correlation, id), (1, 7, on_node(5, 2)));
+
+        let reply = deliver(&two, from, &to_one, || Envelope::Reply {
+            correlation,
+            reply: Reply::Left(true),
+        })
+        .await;
+        assert!(matches!(
+            reply,
+            Envelope::Reply {
+                correlation: 7,
+                reply: Reply::Left(true),
+            }
+        ));
+    }
+}
@@ -0,0 +1,570 @@
+use std::fmt::{Display, Formatter};
+use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use toml::{Table, Value};
+
+use crate::backplane::NodeId;
+use crate::proxy::TrustedProxies;
+use crate::server::RoomLimits;
+use crate::tls::TlsFiles;
+use crate::uploads::UploadConfig;
+
+/// Config file read when none is given and it exists
+const DEFAULT_FILE: &str = "peershare.toml";
+
+/// Prefix of the environment variables, keeps them apart from those of the deployment
+const ENV_PREFIX: &str = "PEERSHARE_";
+
+/// Everything the server can be configured with.
+///
+/// Values are taken from the defaults, then the TOML file, then environment variables
+/// named like the keys in upper case after `PEERSHARE_`, then `--kebab-case` command-line flags.
+/// Durations are in seconds, for the optional limits `0` means none.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Address the server listens on
+    pub addr: String,
+    /// Plain HTTP address redirecting to `addr`, empty turns it off, needs TLS
+    pub redirect_addr: String,
+    /// Listen on this Unix socket instead of `addr`, e.g. behind a local reverse proxy
+    pub unix_socket: PathBuf,
+    /// Addresses and networks of reverse proxies whose forwarding headers are believed
+    pub trusted_proxies: Vec<String>,
+    /// Worker threads serving HTTP
+    pub workers: usize,
+    /// Most rooms, and so sessions, at the same time
+    pub queue_length: usize,
+    /// Most members of one room
+    pub room_capacity: usize,
+    /// How often sessions are pinged
+    pub heartbeat_interval: u64,
+    /// Sessions that did not answer for this long are dropped
+    pub client_timeout: u64,
+    /// Certificate chain and key for HTTPS, both empty serves plain HTTP
+    pub tls_cert: PathBuf,
+    pub tls_key: PathBuf,
+    /// CA bundle clients must present a certificate from, empty does not ask for one
+    pub tls_client_ca: PathBuf,
+    /// Revocation list checked against client certificates
+    pub tls_client_crl: PathBuf,
+    /// Most sessions one client certificate subject may have open, `0` means no limit
+    pub identity_max_sessions: usize,
+    /// How often the certificate files are checked for changes, `0` reloads only on SIGHUP
+    pub tls_watch_interval: u64,
+    /// Directory of the frontend served at `/`
+    pub static_dir: PathBuf,
+    /// Close rooms this long after they were created
+    pub room_max_age: u64,
+    /// Close rooms this long after the last message or signaling command
+    pub room_idle_timeout: u64,
+    /// Warn members this long before a room expires
+    pub room_expiry_warning: u64,
+    /// Most messages kept per room, `0` turns history off
+    pub history_length: usize,
+    pub history_max_age: u64,
+    /// How long a session keeps its rooms after its socket closed, `0` drops it right away
+    pub reconnect_grace: u64,
+    /// How long clients get to go away once the server is asked to stop
+    pub shutdown_drain: u64,
+    /// Rooms are saved here on shutdown and restored on the next start, empty turns it off
+    pub snapshot_file: PathBuf,
+    /// Directory rooms are kept in as they change, empty keeps them in memory only
+    pub store_dir: PathBuf,
+    /// Number of this process among the ones sharing rooms, from 0 to 255.
+    /// Shards take it and the ids after it.
+    pub node_id: u64,
+    /// Chat server actors rooms are spread over, each on its own thread
+    pub shards: u64,
+    /// Redis server the nodes talk through, empty runs a single node
+    pub redis_url: String,
+    /// Directory uploads are kept in, empty turns uploads off
+    pub upload_dir: PathBuf,
+    pub upload_max_size: u64,
+    pub upload_ttl: u64,
+    pub upload_max_downloads: u32,
+    /// Bytes kept on disk at most, unfinished uploads count with their full size
+    pub upload_quota: u64,
+    /// Bytes one session may keep on disk
+    pub upload_session_quota: u64,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            addr: "0.0.0.0:8080".to_owned(),
+            redirect_addr: String::new(),
+            unix_socket: PathBuf::new(),
+            trusted_proxies: Vec::new(),
+            workers: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            queue_length: 20000,
+            room_capacity: 10,
+            heartbeat_interval: 5,
+            client_timeout: 10,
+            tls_cert: PathBuf::new(),
+            tls_key: PathBuf::new(),
+            tls_client_ca: PathBuf::new(),
+            tls_client_crl: PathBuf::new(),
+            identity_max_sessions: 0,
+            tls_watch_interval: 10,
+            static_dir: "./static".into(),
+            room_max_age: 0,
+            room_idle_timeout: 0,
+            room_expiry_warning: 60,
+            history_length: 50,
+            history_max_age: 600,
+            reconnect_grace: 30,
+            shutdown_drain: 5,
+            snapshot_file: PathBuf::new(),
+            store_dir: PathBuf::new(),
+            node_id: 0,
+            shards: 1,
+            redis_url: String::new(),
+            upload_dir: PathBuf::new(),
+            upload_max_size: 100 * 1024 * 1024,
+            upload_ttl: 24 * 60 * 60,
+            upload_max_downloads: 10,
+            upload_quota: 10 * 1024 * 1024 * 1024,
+            upload_session_quota: 1024 * 1024 * 1024,
+        }
+    }
+}
+
+/// What the binary was asked to do
+#[derive(Debug, PartialEq, Eq)]
+pub enum Mode {
+    Run,
+    /// Print the resulting configuration as TOML and exit
+    PrintConfig,
+    Help,
+}
+
+#[derive(Debug)]
+pub struct ConfigError(String);
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Config {
+    /// Load the configuration of this process
+    pub fn load() -> Result<(Config, Mode), ConfigError> {
+        Config::load_from(std::env::args().skip(1), |name| std::env::var(name).ok())
+    }
+
+    pub fn load_from(
+        args: impl IntoIterator<Item = String>,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<(Config, Mode), ConfigError> {
+        let defaults = Config::default().to_table();
+        let mut mode = Mode::Run;
+        let mut file = None;
+        let mut flags = Vec::new();
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--print-config" => mode = Mode::PrintConfig,
+                "-h" | "--help" => mode = Mode::Help,
+                _ => {
+                    let Some(flag) = arg.strip_prefix("--") else {
+                        return Err(ConfigError(format!("unexpected argument {:?}, see --help", arg)));
+                    };
+                    let (name, value) = match flag.split_once('=') {
+                        Some((name, value)) => (name.to_owned(), value.to_owned()),
+                        None => {
+                            let value = args.next().ok_or_else(|| {
+                                ConfigError(format!("missing value for --{}", flag))
+                            })?;
+                            (flag.to_owned(), value)
+                        }
+                    };
+
+                    if name == "config" {
+                        file = Some(PathBuf::from(value));
+                        continue;
+                    }
+                    let key = name.replace('-', "_");
+                    if !defaults.contains_key(&key) {
+                        return Err(ConfigError(format!("unknown flag --{}, see --help", name)));
+                    }
+                    flags.push((key, value));
+                }
+            }
+        }
+        if mode == Mode::Help {
+            return Ok((Config::default(), mode));
+        }
+
+        let mut table = defaults.clone();
+
+        let file = file.or_else(|| env(&format!("{}CONFIG", ENV_PREFIX)).map(PathBuf::from));
+        let file = match file {
+            Some(file) => Some(file),
+            None if Path::new(DEFAULT_FILE).exists() => Some(PathBuf::from(DEFAULT_FILE)),
+            None => None,
+        };
+        if let Some(file) = file {
+            let text = std::fs::read_to_string(&file).map_err(|err| {
+                ConfigError(format!("cant read config file {}: {}", file.display(), err))
+            })?;
+            let parsed: Table = text.parse().map_err(|err| {
+                ConfigError(format!("bad config file {}: {}", file.display(), err))
+            })?;
+            table.extend(parsed);
+        }
+
+        for key in defaults.keys() {
+            let name = format!("{}{}", ENV_PREFIX, key.to_uppercase());
+            if let Some(value) = env(&name) {
+                let value = typed(&defaults, key, &value)
+                    .map_err(|expected| ConfigError(format!("{} must be {}", name, expected)))?;
+                table.insert(key.clone(), value);
+            }
+        }
+
+        for (key, value) in flags {
+            let value = typed(&defaults, &key, &value).map_err(|expected| {
+                ConfigError(format!("--{} must be {}", key.replace('_', "-"), expected))
+            })?;
+            table.insert(key, value);
+        }
+
+        let config = Config::deserialize(Value::Table(table))
+            .map_err(|err| ConfigError(format!("bad configuration: {}", err)))?;
+        config.validate()?;
+
+        Ok((config, mode))
+    }
+
+    fn to_table(&self) -> Table {
+        Table::try_from(self).unwrap_or_default()
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let check = |ok: bool, message: &str| {
+            if ok {
+                Ok(())
+            } else {
+                Err(ConfigError(message.to_owned()))
+            }
+        };
+
+        if self.unix_socket.as_os_str().is_empty() {
+            check(
+                self.addr.to_socket_addrs().is_ok_and(|mut addrs| addrs.next().is_some()),
+                &format!("addr {:?} is not an address to listen on", self.addr),
+            )?;
+        } else {
+            check(cfg!(unix), "unix_socket is only supported on unix")?;
+            check(self.tls().is_none(), "tls_cert and tls_key can not be used with unix_socket")?;
+        }
+        if !self.redirect_addr.is_empty() {
+            check(self.tls().is_some(), "redirect_addr needs tls_cert and tls_key")?;
+            check(
+                self.redirect_addr.to_socket_addrs().is_ok_and(|mut addrs| addrs.next().is_some()),
+                &format!("redirect_addr {:?} is not an address to listen on", self.redirect_addr),
+            )?;
+            check(self.redirect_addr != self.addr, "redirect_addr must differ from addr")?;
+        }
+        self.trusted_proxies().map_err(ConfigError)?;
+        check(self.workers > 0, "workers must be at least 1")?;
+        check(self.queue_length > 0, "queue_length must be at least 1")?;
+        check(self.room_capacity > 1, "room_capacity must be at least 2")?;
+        check(self.heartbeat_interval > 0, "heartbeat_interval must be at least 1")?;
+        check(
+            self.client_timeout > self.heartbeat_interval,
+            "client_timeout must be longer than heartbeat_interval",
+        )?;
+        check(
+            self.tls_cert.as_os_str().is_empty() == self.tls_key.as_os_str().is_empty(),
+            "tls_cert and tls_key must be set together",
+        )?;
+        if let Some(files) = self.tls() {
+            check(files.cert.is_file(), &format!("tls_cert {} does not exist", files.cert.display()))?;
+            check(files.key.is_file(), &format!("tls_key {} does not exist", files.key.display()))?;
+            if let Some(ca) = &files.client_ca {
+                check(ca.is_file(), &format!("tls_client_ca {} does not exist", ca.display()))?;
+            }
+            if let Some(crl) = &files.client_crl {
+                check(files.client_ca.is_some(), "tls_client_crl needs tls_client_ca")?;
+                check(crl.is_file(), &format!("tls_client_crl {} does not exist", crl.display()))?;
+            }
+        } else {
+            check(
+                self.tls_client_ca.as_os_str().is_empty(),
+                "tls_client_ca needs tls_cert and tls_key",
+            )?;
+        }
+        check(
+            self.static_dir.is_dir(),
+            &format!("static_dir {} does not exist", self.static_dir.display()),
+        )?;
+        check(
+            self.snapshot_file().is_none() || self.store_dir().is_none(),
+            "snapshot_file can not be used with store_dir, the store already keeps the rooms",
+        )?;
+        check(self.shards > 0, "shards must be at least 1")?;
+        check(
+            self.queue_length >= self.shards as usize,
+            "queue_length must be at least shards, every shard needs a room",
+        )?;
+        check(
+            NodeId::try_from(self.node_id.saturating_add(self.shards - 1)).is_ok(),
+            "node_id and the shards after it must be at most 255",
+        )?;
+        if self.uploads().is_some() {
+            check(self.upload_ttl > 0, "upload_ttl must be at least 1")?;
+            check(self.upload_max_downloads > 0, "upload_max_downloads must be at least 1")?;
+        }
+
+        Ok(())
+    }
+
+    pub fn to_toml(&self) -> String {
+        toml::to_string(self).unwrap_or_default()
+    }
+
+    /// Certificate files, `None` serves plain HTTP
+    pub fn tls(&self) -> Option<TlsFiles> {
+        let path = |path: &PathBuf| Some(path.clone()).filter(|path| !path.as_os_str().is_empty());
+
+        Some(TlsFiles {
+            cert: path(&self.tls_cert)?,
+            key: self.tls_key.clone(),
+            client_ca: path(&self.tls_client_ca),
+            client_crl: path(&self.tls_client_crl),
+        })
+    }
+
+    /// Most sessions per client certificate subject
+    pub fn identity_max_sessions(&self) -> Option<usize> {
+        Some(self.identity_max_sessions).filter(|max| *max > 0)
+    }
+
+    pub fn tls_watch_interval(&self) -> Option<Duration> {
+        Some(Duration::from_secs(self.tls_watch_interval)).filter(|interval| !interval.is_zero())
+    }
+
+    /// Plain HTTP address to redirect from and the HTTPS port to redirect to
+    pub fn redirect(&self) -> Option<(&str, u16)> {
+        if self.redirect_addr.is_empty() {
+            return None;
+        }
+        let port = self.addr.to_socket_addrs().ok()?.next()?.port();
+
+        Some((&self.redirect_addr, port))
+    }
+
+    /// Unix socket to listen on instead of `addr`
+    pub fn unix_socket(&self) -> Option<&Path> {
+        Some(self.unix_socket.as_path()).filter(|path| !path.as_os_str().is_empty())
+    }
+
+    pub fn trusted_proxies(&self) -> Result<TrustedProxies, String> {
+        TrustedProxies::parse(&self.trusted_proxies, self.unix_socket().is_some())
+    }
+
+    pub fn heartbeat_interval(&self) -> Duration {
+        Duration::from_secs(self.heartbeat_interval)
+    }
+
+    pub fn client_timeout(&self) -> Duration {
+        Duration::from_secs(self.client_timeout)
+    }
+
+    pub fn reconnect_grace(&self) -> Duration {
+        Duration::from_secs(self.reconnect_grace)
+    }
+
+    pub fn shutdown_drain(&self) -> Duration {
+        Duration::from_secs(self.shutdown_drain)
+    }
+
+    /// Where rooms are kept across a restart
+    pub fn snapshot_file(&self) -> Option<&Path> {
+        Some(self.snapshot_file.as_path()).filter(|path| !path.as_os_str().is_empty())
+    }
+
+    /// Where rooms are kept as they change
+    pub fn store_dir(&self) -> Option<&Path> {
+        Some(self.store_dir.as_path()).filter(|path| !path.as_os_str().is_empty())
+    }
+
+    pub fn node_id(&self) -> NodeId {
+        NodeId::try_from(self.node_id).unwrap_or_default()
+    }
+
+    /// Node ids of the shards of this process with their share of `queue_length`,
+    /// the first shards take one room more when it does not split evenly
+    pub fn shard_nodes(&self) -> impl Iterator<Item = (NodeId, usize)> {
+        let shards = self.shards as usize;
+        let (base, rest) = (self.queue_length / shards, self.queue_length % shards);
+        (self.node_id..self.node_id + self.shards)
+            .filter_map(|node| NodeId::try_from(node).ok())
+            .enumerate()
+            .map(move |(index, node)| (node, base + usize::from(index < rest)))
+    }
+
+    /// Redis server of the backplane, `None` keeps everything in this process
+    pub fn redis_url(&self) -> Option<&str> {
+        Some(self.redis_url.as_str()).filter(|url| !url.is_empty())
+    }
+
+    pub fn room_limits(&self) -> RoomLimits {
+        let limit = |secs| Some(Duration::from_secs(secs)).filter(|limit| !limit.is_zero());
+
+        RoomLimits {
+            capacity: self.room_capacity,
+            max_age: limit(self.room_max_age),
+            idle_timeout: limit(self.room_idle_timeout),
+            warning: Duration::from_secs(self.room_expiry_warning),
+            history_len: self.history_length,
+            history_age: Duration::from_secs(self.history_max_age),
+        }
+    }
+
+    /// Upload limits, `None` when uploads are turned off
+    pub fn uploads(&self) -> Option<UploadConfig> {
+        if self.upload_dir.as_os_str().is_empty() {
+            return None;
+        }
+
+        Some(UploadConfig {
+            dir: self.upload_dir.clone(),
+            max_size: self.upload_max_size,
+            ttl: Duration::from_secs(self.upload_ttl),
+            max_downloads: self.upload_max_downloads,
+            quota: self.upload_quota,
+            session_quota: self.upload_session_quota,
+        })
+    }
+}
+
+/// Parse a value given as text into the type of the key's default value
+fn typed(defaults: &Table, key: &str, value: &str) -> Result<Value, &'static str> {
+    match defaults.get(key) {
+        Some(Value::Integer(_)) => value
+            .parse::<u64>()
+            .ok()
+            .and_then(|value| i64::try_from(value).ok())
+            .map(Value::Integer)
+            .ok_or("a whole number"),
+        // lists are comma separated
+        Some(Value::Array(_)) => Ok(Value::Array(
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(|item| Value::String(item.to_owned()))
+                .collect(),
+        )),
+        _ => Ok(Value::String(value.to_owned())),
+    }
+}
+
+pub fn usage() -> String {
+    let keys: Vec<String> = Config::default()
+        .to_table()
+        .keys()
+        .map(|key| format!("  --{} <value>", key.replace('_', "-")))
+        .collect();
+
+    format!(
+        "usage: peershare [--config <file>] [--print-config] [--<key> <value>...]\n\n\
+         Settings come from the defaults, then {} or --config, then environment\n\
+         variables named like the keys in upper case after {}, then flags.\n\n{}",
+        DEFAULT_FILE,
+        ENV_PREFIX,
+        keys.join("\n")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+
+    use super::{Config, Mode};
+
+    /// An empty config file, so `peershare.toml` in the working directory is never read
+    fn empty_file() -> PathBuf {
+        let path = std::env::temp_dir().join(format!("peershare-config-{}.toml", std::process::id()));
+        std::fs::write(&path, "").unwrap();
+        path
+    }
+
+    fn load(args: &[&str], env: &[(&str, &str)]) -> Result<Config, String> {
+        let file = empty_file();
+        let env: HashMap<String, String> = env
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .chain([("PEERSHARE_CONFIG".to_owned(), file.display().to_string())])
+            .collect();
+        let args = args.iter().map(|arg| arg.to_string());
+
+        Config::load_from(args, |name| env.get(name).cloned())
+            .map(|(config, _)| config)
+            .map_err(|err| err.to_string())
+    }
+
+    #[test]
+    fn flags_override_env_override_defaults() {
+        let dir = env!("CARGO_MANIFEST_DIR");
+        let env = [
+            ("PEERSHARE_STATIC_DIR", dir),
+            ("PEERSHARE_WORKERS", "3"),
+            ("PEERSHARE_QUEUE_LENGTH", "7"),
+            ("PEERSHARE_TRUSTED_PROXIES", "10.0.0.0/8, ::1"),
+            ("WORKERS", "9"),
+            ("ADDR", "not an address"),
+        ];
+
+        let config = load(&["--workers", "5", "--room-capacity=4"], &env).unwrap();
+        assert_eq!(config.workers, 5);
+        assert_eq!(config.queue_length, 7);
+        assert_eq!(config.room_capacity, 4);
+        assert_eq!(config.heartbeat_interval, 5);
+        assert_eq!(config.trusted_proxies, ["10.0.0.0/8", "::1"]);
+        assert!(config.tls().is_none());
+    }
+
+    #[test]
+    fn bad_values_are_reported_by_name() {
+        let err = load(&[], &[("PEERSHARE_WORKERS", "many")]).unwrap_err();
+        assert_eq!(err, "PEERSHARE_WORKERS must be a whole number");
+
+        let err = load(&["--queue-lenght", "5"], &[]).unwrap_err();
+        assert_eq!(err, "unknown flag --queue-lenght, see --help");
+
+        let err = load(&["--workers"], &[]).unwrap_err();
+        assert_eq!(err, "missing value for --workers");
+
+        let env = [("PEERSHARE_STATIC_DIR", env!("CARGO_MANIFEST_DIR"))];
+        let err = load(&["--node-id", "250", "--shards", "8"], &env).unwrap_err();
+        assert_eq!(err, "node_id and the shards after it must be at most 255");
+
+        let err = load(&["--queue-length", "2", "--shards", "3"], &env).unwrap_err();
+        assert_eq!(err, "queue_length must be at least shards, every shard needs a room");
+    }
+
+    #[test]
+    fn shards_split_the_queue_length() {
+        let env = [("PEERSHARE_STATIC_DIR", env!("CARGO_MANIFEST_DIR"))];
+        let config = load(&["--queue-length", "10", "--shards", "3", "--node-id", "4"], &env).unwrap();
+
+        let shards: Vec<_> = config.shard_nodes().collect();
+        assert_eq!(shards, [(4, 4), (5, 3), (6, 3)]);
+    }
+
+    #[test]
+    fn help_skips_loading() {
+        let (_, mode) = Config::load_from(["--help".to_owned()], |_| None).unwrap();
+        assert_eq!(mode, Mode::Help);
+    }
+}
@@ -0,0 +1,292 @@
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Server assigned ordering data every relayed event carries
+#[derive(Debug, Clone, Copy)]
+pub struct Stamp {
+    /// Monotonic per-room sequence number
+    pub seq: u64,
+    /// Unique message id
+    pub id: u64,
+    /// Milliseconds since the unix epoch when the server relayed the event
+    pub timestamp: u128,
+}
+
+impl Stamp {
+    pub fn new(seq: u64, id: u64) -> Stamp {
+        Stamp {
+            seq,
+            id,
+            timestamp: unix_millis(),
+        }
+    }
+}
+
+impl Display for Stamp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {} {}", self.seq, self.id, self.timestamp)
+    }
+}
+
+/// Events that are recorded in room history and saved with it
+const KEPT_EVENTS: &[&str] = &["/message", "/invite", "/upload"];
+
+/// Event of direct messages, kept apart so signaling never evicts room events
+const DIRECT_EVENT: &str = "/direct_message";
+
+/// Most direct messages a room keeps for reconnecting sessions
+const MAX_DIRECTS: usize = 64;
+
+/// One event kept for sessions that join or reconnect later
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub stamp: Stamp,
+    /// Event name as sent to clients, e.g. `/message`
+    pub event: &'static str,
+    pub sender: usize,
+    /// Only this session may see the event, `None` for the whole room
+    pub recipient: Option<usize>,
+    pub text: String,
+    received: Instant,
+}
+
+impl Entry {
+    pub fn new(
+        stamp: Stamp,
+        event: &'static str,
+        sender: usize,
+        recipient: Option<usize>,
+        text: &str,
+    ) -> Entry {
+        Entry {
+            stamp,
+            event,
+            sender,
+            recipient,
+            text: text.to_owned(),
+            received: Instant::now(),
+        }
+    }
+}
+
+impl Entry {
+    /// An event kept from before a restart, `None` when the event is not one rooms keep
+    pub fn restore(
+        stamp: Stamp,
+        event: &str,
+        sender: usize,
+        recipient: Option<usize>,
+        text: &str,
+    ) -> Option<Entry> {
+        let event = KEPT_EVENTS.iter().find(|kept| **kept == event)?;
+
+        Some(Entry {
+            stamp,
+            event,
+            sender,
+            recipient,
+            text: text.to_owned(),
+            received: instant_at(stamp.timestamp),
+        })
+    }
+}
+
+impl Entry {
+    /// The event as it was sent to clients
+    pub fn line(&self, room: usize) -> String {
+        format!(
+            "{} {} {} {} {}",
+            self.event, room, self.stamp, self.sender, self.text
+        )
+    }
+
+    fn visible_to(&self, id: usize) -> bool {
+        self.sender == id || self.recipient.is_none_or(|recipient| recipient == id)
+    }
+}
+
+/// Bounded buffer of the most recent events of a room
+#[derive(Debug)]
+pub struct History {
+    entries: VecDeque<Entry>,
+    /// Direct messages, capped on their own and never saved
+    directs: VecDeque<Entry>,
+    /// Most events kept, `0` turns history off
+    pub max_len: usize,
+    /// Events older than this are dropped
+    pub max_age: Duration,
+}
+
+impl History {
+    pub fn new(max_len: usize, max_age: Duration) -> History {
+        History {
+            entries: VecDeque::new(),
+            directs: VecDeque::new(),
+            max_len,
+            max_age,
+        }
+    }
+}
+
+impl History {
+    pub fn push(&mut self, entry: Entry) {
+        if self.max_len == 0 {
+            return;
+        }
+
+        match entry.event {
+            DIRECT_EVENT => self.directs.push_back(entry),
+            _ => self.entries.push_back(entry),
+        }
+        self.prune();
+    }
+
+    /// Drop events over the length or age limit
+    pub fn prune(&mut self) {
+        let now = Instant::now();
+        for (entries, max_len) in [
+            (&mut self.entries, self.max_len),
+            (&mut self.directs, self.max_len.min(MAX_DIRECTS)),
+        ] {
+            while entries.len() > max_len {
+                entries.pop_front();
+            }
+
+            while entries
+                .front()
+                .is_some_and(|entry| now.duration_since(entry.received) > self.max_age)
+            {
+                entries.pop_front();
+            }
+        }
+    }
+
+    /// Every kept room event from oldest to newest, direct messages are left out
+    pub fn entries(&self) -> impl Iterator<Item = &Entry> {
+        self.entries.iter()
+    }
+
+    /// Chat messages from oldest to newest
+    pub fn chat(&self) -> impl Iterator<Item = &Entry> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.event == "/message")
+    }
+
+    /// Events after sequence number `seq` that session `id` may see, oldest first
+    pub fn since(&self, seq: u64, id: usize) -> impl Iterator<Item = &Entry> {
+        let mut entries: Vec<&Entry> = self
+            .entries
+            .iter()
+            .chain(self.directs.iter())
+            .filter(|entry| entry.stamp.seq > seq && entry.visible_to(id))
+            .collect();
+        entries.sort_by_key(|entry| entry.stamp.seq);
+
+        entries.into_iter()
+    }
+}
+
+pub fn unix_millis() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_millis())
+        .unwrap_or_default()
+}
+
+/// Milliseconds since the unix epoch at `instant`
+pub fn unix_millis_at(instant: Instant) -> u128 {
+    unix_millis().saturating_sub(instant.elapsed().as_millis())
+}
+
+/// The instant at `millis` since the unix epoch, times in the future are now
+pub fn instant_at(millis: u128) -> Instant {
+    let ago = Duration::from_millis(unix_millis().saturating_sub(millis) as u64);
+    Instant::now().checked_sub(ago).unwrap_or_else(Instant::now)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(seq: u64, event: &'static str) -> Entry {
+        Entry::new(Stamp::new(seq, seq), event, 1, None, &format!("text {}", seq))
+    }
+
+    fn seqs<'a>(entries: impl Iterator<Item = &'a Entry>) -> Vec<u64> {
+        entries.map(|entry| entry.stamp.seq).collect()
+    }
+
+    #[test]
+    fn oldest_events_go_over_the_length_limit() {
+        let mut history = History::new(3, Duration::from_secs(600));
+        for seq in 1..=5 {
+            history.push(entry(seq, "/message"));
+        }
+        assert_eq!(seqs(history.entries()), [3, 4, 5]);
+
+        history.max_len = 1;
+        history.prune();
+        assert_eq!(seqs(history.entries()), [5]);
+
+        let mut off = History::new(0, Duration::from_secs(600));
+        off.push(entry(1, "/message"));
+        assert_eq!(off.entries().count(), 0);
+    }
+
+    #[test]
+    fn events_older_than_the_age_limit_are_dropped() {
+        let mut history = History::new(10, Duration::from_secs(60));
+        let old = Stamp {
+            seq: 1,
+            id: 1,
+            timestamp: unix_millis() - 120_000,
+        };
+        history.push(Entry::restore(old, "/message", 1, None, "old").unwrap());
+        history.push(entry(2, "/message"));
+
+        assert_eq!(seqs(history.entries()), [2]);
+        assert!(Entry::restore(old, "/members", 1, None, "[]").is_none());
+    }
+
+    #[test]
+    fn chat_replays_messages_oldest_first() {
+        let mut history = History::new(10, Duration::from_secs(600));
+        history.push(entry(1, "/message"));
+        history.push(entry(2, "/invite"));
+        history.push(entry(3, "/message"));
+        history.push(Entry::new(Stamp::new(4, 4), "/direct_message", 1, Some(2), "psst"));
+
+        assert_eq!(seqs(history.chat()), [1, 3]);
+        assert_eq!(seqs(history.since(1, 2)), [2, 3, 4]);
+        assert_eq!(seqs(history.since(1, 3)), [2, 3]);
+        assert_eq!(seqs(history.entries()), [1, 2, 3]);
+        let first = history.chat().next().unwrap();
+        assert_eq!(first.line(9), format!("/message 9 1 1 {} 1 text 1", first.stamp.timestamp));
+    }
+
+    #[test]
+    fn direct_messages_do_not_evict_room_events() {
+        let mut history = History::new(3, Duration::from_secs(600));
+        history.push(entry(1, "/message"));
+        history.push(entry(2, "/message"));
+        for seq in 3..=100 {
+            history.push(Entry::new(Stamp::new(seq, seq), "/direct_message", 1, Some(2), "ice"));
+        }
+        history.push(entry(101, "/message"));
+
+        assert_eq!(seqs(history.chat()), [1, 2, 101]);
+        assert_eq!(seqs(history.since(0, 2)), [1, 2, 98, 99, 100, 101]);
+
+        history.max_len = 100;
+        for seq in 102..=200 {
+            history.push(Entry::new(Stamp::new(seq, seq), "/direct_message", 1, Some(2), "ice"));
+        }
+        assert_eq!(history.since(101, 2).count(), MAX_DIRECTS);
+        assert_eq!(seqs(history.entries()), [1, 2, 101]);
+
+        let stamp = Stamp::new(1, 1);
+        assert!(Entry::restore(stamp, "/direct_message", 1, Some(2), "sdp").is_none());
+    }
+}
@@ -1,39 +1,314 @@
-use std::{env, sync::Mutex};
+use std::{future::Future, io, path::{Path, PathBuf}, process, time::Duration};
 
 use actix::*;
 use actix_files::Files;
-use actix_web::{middleware::Logger, web, web::Data, App, HttpServer};
-use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
+use actix_web::{
+    http::Method,
+    middleware::{DefaultHeaders, Logger},
+    dev::{Service, ServerHandle, ServiceResponse},
+    http::header,
+    web, App, HttpMessage, HttpResponse, HttpServer,
+};
+use futures_util::future::{join_all, ready, select, Either, TryFutureExt};
 
-mod queue;
+use config::{Config, Mode};
+use proxy::Client;
+use store::RoomStore;
+
+/// Like the default access log but with the client address resolved behind trusted proxies
+const LOG_FORMAT: &str = r#"%{client}xi "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T"#;
+
+mod allocator;
+mod backplane;
+mod config;
+mod history;
+mod proxy;
 mod reserr;
+mod router;
 mod routes;
 mod server;
 mod session;
+mod snapshot;
+mod store;
+mod tls;
+mod transfer;
+mod tus;
+mod uploads;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenv::dotenv().ok();
-    let queue = Data::new(Mutex::new(queue::Queue::new(env::var("QUEUE_LENGHT").unwrap().parse::<usize>().unwrap())));
+    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
+
+    let config = match Config::load() {
+        Ok((_, Mode::Help)) => {
+            println!("{}", config::usage());
+            return Ok(());
+        }
+        Ok((config, Mode::PrintConfig)) => {
+            print!("{}", config.to_toml());
+            return Ok(());
+        }
+        Ok((config, Mode::Run)) => config,
+        Err(err) => {
+            eprintln!("peershare: {}", err);
+            process::exit(2);
+        }
+    };
+
+    // rooms of the last run, from the shutdown snapshot or the store
+    let mut saved = snapshot::Snapshot::default();
+    if let Some(path) = config.snapshot_file() {
+        match snapshot::take(path) {
+            Ok(Some(snapshot)) => saved = snapshot,
+            Ok(None) => {}
+            Err(err) => log::error!("cant restore rooms from {}: {}", path.display(), err),
+        }
+    }
+    if let Some(dir) = config.store_dir() {
+        saved = store::DirStore::open(dir)?.load()?;
+    }
+
+    // start a chat server actor per shard on its own arbiter, each owns a room allocator
+    let hub = backplane::Hub::default();
+    let mut shards = Vec::new();
+    for (node, capacity) in config.shard_nodes() {
+        let queue = allocator::RoomAllocator::new(capacity, node);
+        let mut backplane = hub.node(node);
+        if let Some(url) = config.redis_url() {
+            backplane = backplane.or(backplane::Redis::connect(url, node)?);
+        }
+        let store = config.store_dir().map(store::DirStore::open).transpose()?;
+        let restored = saved.take_node(node);
+        let limits = config.room_limits();
+        let reconnect_grace = config.reconnect_grace();
+        let identity_limit = config.identity_max_sessions();
 
-    // start chat server actor
-    let server = server::ChatServer::new(queue.clone()).start();
+        shards.push(server::ChatServer::start_in_arbiter(&Arbiter::new().handle(), move |_| {
+            let backplane = Box::new(backplane);
+            let mut server =
+                server::ChatServer::new(queue, limits, reconnect_grace, identity_limit, backplane);
+            if let Some(store) = store {
+                server.persist(Box::new(store));
+            }
+            server.restore(restored);
+            server
+        }));
+    }
+    if !saved.rooms.is_empty() {
+        log::warn!("not restoring {} rooms of other nodes", saved.rooms.len());
+    }
+    let server = router::Router::new(config.node_id(), shards);
 
-    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
-    builder
-        .set_private_key_file("server.key", SslFiletype::PEM)
-        .unwrap();
-    builder.set_certificate_chain_file("server.crt").unwrap();
+    // the upload endpoint is only served when a directory for the files is configured
+    let uploads = match config.uploads() {
+        Some(uploads) => {
+            std::fs::create_dir_all(&uploads.dir)?;
+            let store = uploads::UploadStore::new(uploads.clone()).start();
+            Some((uploads, store))
+        }
+        None => None,
+    };
 
-    HttpServer::new(move || {
+    // without certificates TLS is left to a reverse proxy in front of us
+    let acceptor = match config.tls() {
+        Some(files) => {
+            let (builder, watcher) = tls::CertWatcher::new(files, config.tls_watch_interval())?;
+            let watcher = watcher.start();
+            reload_on_hangup(watcher)?;
+            Some(builder)
+        }
+        None => None,
+    };
+
+    let redirect = config.redirect().map(|(addr, port)| (addr.to_owned(), port));
+    let https_port = redirect.as_ref().map(|(_, port)| *port);
+    let proxies = config.trusted_proxies().map_err(io::Error::other)?;
+    let addr = config.addr.clone();
+    let unix_socket = config.unix_socket().map(Path::to_path_buf);
+    let workers = config.workers;
+    let drain = config.shutdown_drain();
+    let snapshot_file = config.snapshot_file().map(Path::to_path_buf);
+    let chat = server.clone();
+    let config = web::Data::new(config);
+
+    let http = HttpServer::new(move || {
         App::new()
-            .app_data(Data::clone(&queue))
             .app_data(web::Data::new(server.clone()))
+            .app_data(config.clone())
             .route("/ws", web::get().to(routes::chat_route))
-            .service(Files::new("/", "./static").index_file("index.html"))
-            .wrap(Logger::default())
+            .configure(|cfg| {
+                if let Some((uploads, store)) = &uploads {
+                    cfg.app_data(web::Data::new(uploads.clone()))
+                        .app_data(web::Data::new(store.clone()))
+                        .route("/upload", web::post().to(routes::upload_route))
+                        .route("/files/{id}", web::get().to(routes::download_route))
+                        .service(
+                            web::scope("/tus")
+                                .wrap(DefaultHeaders::new().add(("Tus-Resumable", tus::TUS_VERSION)))
+                                .route("", web::method(Method::OPTIONS).to(tus::options_route))
+                                .route("", web::post().to(tus::create_route))
+                                .route("/{id}", web::head().to(tus::head_route))
+                                .route("/{id}", web::patch().to(tus::patch_route))
+                                .route("/{id}", web::delete().to(tus::delete_route)),
+                        );
+                }
+            })
+            .service(Files::new("/", &config.static_dir).index_file("index.html"))
+            .wrap(Logger::new(LOG_FORMAT).custom_request_replace("client", |req| {
+                req.extensions()
+                    .get::<Client>()
+                    .and_then(|client| client.ip)
+                    .map_or("-".to_owned(), |ip| ip.to_string())
+            }))
+            .wrap_fn({
+                let proxies = proxies.clone();
+                move |req, srv| {
+                    // the redirect listener shares this app, its requests are the plain ones
+                    let client = proxies.resolve(
+                        req.peer_addr().map(|addr| addr.ip()),
+                        req.headers(),
+                        req.app_config().secure(),
+                    );
+                    let redirect = https_port.filter(|_| client.scheme == "http");
+                    req.extensions_mut().insert(client);
+
+                    match redirect {
+                        Some(port) => {
+                            let host = req
+                                .headers()
+                                .get(header::HOST)
+                                .and_then(|host| host.to_str().ok())
+                                .or(req.uri().host())
+                                .unwrap_or_default();
+                            let path = req.uri().path_and_query().map_or("/", |path| path.as_str());
+                            let location = proxy::https_url(host, path, port);
+
+                            let res = HttpResponse::PermanentRedirect()
+                                .insert_header((header::LOCATION, location))
+                                .finish();
+                            Either::Left(ready(Ok(req.into_response(res).map_into_right_body())))
+                        }
+                        None => Either::Right(srv.call(req).map_ok(ServiceResponse::map_into_left_body)),
+                    }
+                }
+            })
+    })
+    .on_connect(tls::peer_identity)
+    .workers(workers)
+    .disable_signals();
+
+    let http = match (unix_socket, acceptor) {
+        #[cfg(unix)]
+        (Some(path), _) => {
+            remove_stale_socket(&path)?;
+            http.bind_uds(path)?
+        }
+        (_, Some(builder)) => {
+            let http = http.bind_openssl(addr, builder)?;
+            match redirect {
+                Some((redirect_addr, _)) => http.bind(redirect_addr)?,
+                None => http,
+            }
+        }
+        (_, None) => http.bind(addr)?,
+    };
+
+    let http = http.run();
+    let terminated = termination()?;
+    actix_web::rt::spawn(shut_down(terminated, http.handle(), chat, drain, snapshot_file));
+
+    http.await
+}
+
+/// Once `terminated` resolves stop taking websockets, send the sessions away and give them
+/// `drain` to go. The rooms of all shards are saved to the store and `snapshot_file` before
+/// the server stops.
+async fn shut_down(
+    terminated: impl Future<Output = ()>,
+    http: ServerHandle,
+    chat: router::Router,
+    drain: Duration,
+    snapshot_file: Option<PathBuf>,
+) {
+    terminated.await;
+    log::info!("shutting down in {}s", drain.as_secs());
+
+    let shards = chat.shards();
+    let told = join_all(shards.iter().map(|shard| shard.send(server::Shutdown { drain }))).await;
+    if told.iter().all(Result::is_ok) {
+        actix_web::rt::time::sleep(drain).await;
+        join_all(shards.iter().map(|shard| shard.send(server::Save))).await;
+
+        if let Some(path) = snapshot_file {
+            let taken = join_all(shards.iter().map(|shard| shard.send(server::TakeSnapshot))).await;
+            match taken.into_iter().collect::<Result<Vec<_>, _>>() {
+                Ok(taken) => {
+                    let mut snapshot = snapshot::Snapshot::default();
+                    for shard in taken {
+                        snapshot.sessions.extend(shard.sessions);
+                        snapshot.rooms.extend(shard.rooms);
+                    }
+                    match snapshot::save(&path, &snapshot) {
+                        Ok(()) => log::info!("saved {} rooms to {}", snapshot.rooms.len(), path.display()),
+                        Err(err) => log::error!("cant save rooms to {}: {}", path.display(), err),
+                    }
+                }
+                Err(_) => log::error!("chat server unavailable, rooms not saved"),
+            }
+        }
+    }
+
+    http.stop(true).await;
+}
+
+/// Resolves on SIGTERM or Ctrl-C
+#[cfg(unix)]
+fn termination() -> io::Result<impl Future<Output = ()>> {
+    use actix_web::rt::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+
+    Ok(async move {
+        select(Box::pin(terminate.recv()), Box::pin(interrupt.recv())).await;
     })
-    .bind_openssl(env::var("ADDR").unwrap(), builder)?
-    .run()
-    .await
+}
+
+#[cfg(not(unix))]
+fn termination() -> io::Result<impl Future<Output = ()>> {
+    Ok(async {
+        let _ = actix_web::rt::signal::ctrl_c().await;
+    })
+}
+
+/// A socket left behind by an earlier run would make binding fail
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path),
+        _ => Ok(()),
+    }
+}
+
+/// Reload the certificate when the process receives SIGHUP
+#[cfg(unix)]
+fn reload_on_hangup(watcher: Addr<tls::CertWatcher>) -> io::Result<()> {
+    use actix_web::rt::signal::unix::{signal, SignalKind};
+
+    let mut hangup = signal(SignalKind::hangup())?;
+    actix_web::rt::spawn(async move {
+        while hangup.recv().await.is_some() {
+            watcher.do_send(tls::Reload);
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn reload_on_hangup(_: Addr<tls::CertWatcher>) -> io::Result<()> {
+    Ok(())
 }
@@ -0,0 +1,180 @@
+use std::net::IpAddr;
+
+use actix_web::http::header::HeaderMap;
+
+/// Where a request really came from once trusted reverse proxies are taken into account
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Client {
+    /// `None` when the request came over a Unix socket without forwarding headers
+    pub ip: Option<IpAddr>,
+    /// `http` or `https` as the client used it
+    pub scheme: &'static str,
+}
+
+/// Networks of reverse proxies whose `X-Forwarded-For` and `X-Forwarded-Proto` headers are believed
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies {
+    networks: Vec<(IpAddr, u8)>,
+    /// Requests over a Unix socket can only come from a local proxy
+    unix_socket: bool,
+}
+
+impl TrustedProxies {
+    /// Parse addresses and `<address>/<prefix>` networks
+    pub fn parse(list: &[String], unix_socket: bool) -> Result<TrustedProxies, String> {
+        let networks = list
+            .iter()
+            .map(|entry| {
+                let bad = || format!("trusted proxy {:?} is not an address or network", entry);
+                let (addr, prefix) = entry.split_once('/').unwrap_or((entry, ""));
+                let addr: IpAddr = addr.trim().parse().map_err(|_| bad())?;
+                let max = if addr.is_ipv4() { 32 } else { 128 };
+                let prefix = match prefix.trim() {
+                    "" => max,
+                    prefix => prefix.parse().ok().filter(|prefix| *prefix <= max).ok_or_else(bad)?,
+                };
+
+                Ok((addr, prefix))
+            })
+            .collect::<Result<_, String>>()?;
+
+        Ok(TrustedProxies {
+            networks,
+            unix_socket,
+        })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        self.networks.iter().any(|(network, prefix)| match (network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(*prefix)).unwrap_or(0);
+                u32::from(*network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(*prefix)).unwrap_or(0);
+                u128::from(*network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        })
+    }
+
+    /// Resolve the client of a request from `peer`, which reached us over TLS or not.
+    /// Forwarding headers of untrusted peers are ignored, `X-Forwarded-For` is read
+    /// from the right so a client can not pose as someone else by sending its own.
+    pub fn resolve(&self, peer: Option<IpAddr>, headers: &HeaderMap, tls: bool) -> Client {
+        let direct = Client {
+            ip: peer,
+            scheme: if tls { "https" } else { "http" },
+        };
+        let trusted = match peer {
+            Some(ip) => self.contains(ip),
+            None => self.unix_socket,
+        };
+        if !trusted {
+            return direct;
+        }
+
+        let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+        let forwarded: Vec<IpAddr> = header("X-Forwarded-For")
+            .map(|list| {
+                list.split(',')
+                    .filter_map(|ip| ip.trim().parse().ok())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let ip = forwarded
+            .iter()
+            .rev()
+            .find(|ip| !self.contains(**ip))
+            .or(forwarded.first())
+            .copied()
+            .or(direct.ip);
+        let scheme = match header("X-Forwarded-Proto").map(|proto| proto.trim()) {
+            Some("https") => "https",
+            Some("http") => "http",
+            _ => direct.scheme,
+        };
+
+        Client { ip, scheme }
+    }
+}
+
+/// Where a plain HTTP request to `host` and `path` is redirected to, `port` is the HTTPS port
+pub fn https_url(host: &str, path: &str, port: u16) -> String {
+    // drop the port of the plain listener, keeping IPv6 literals intact
+    let host = match host.rfind(':') {
+        Some(colon) if !host[colon..].contains(']') => &host[..colon],
+        _ => host,
+    };
+
+    if port == 443 {
+        format!("https://{}{}", host, path)
+    } else {
+        format!("https://{}:{}{}", host, port, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+
+    use super::{https_url, Client, TrustedProxies};
+
+    fn headers(list: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in list {
+            headers.insert(
+                HeaderName::from_static(name),
+                HeaderValue::from_static(value),
+            );
+        }
+        headers
+    }
+
+    fn proxies(list: &[&str]) -> TrustedProxies {
+        let list: Vec<String> = list.iter().map(|entry| entry.to_string()).collect();
+        TrustedProxies::parse(&list, false).unwrap()
+    }
+
+    #[test]
+    fn forwarding_headers_only_count_from_trusted_proxies() {
+        let proxies = proxies(&["10.0.0.0/8", "::1"]);
+
+        let forwarded = headers(&[
+            ("x-forwarded-for", "6.6.6.6, 1.2.3.4, 10.0.0.7"),
+            ("x-forwarded-proto", "https"),
+        ]);
+        assert_eq!(
+            proxies.resolve(Some("10.1.2.3".parse().unwrap()), &forwarded, false),
+            Client {
+                ip: Some("1.2.3.4".parse().unwrap()),
+                scheme: "https"
+            }
+        );
+
+        assert_eq!(
+            proxies.resolve(Some("8.8.8.8".parse().unwrap()), &forwarded, false),
+            Client {
+                ip: Some("8.8.8.8".parse().unwrap()),
+                scheme: "http"
+            }
+        );
+    }
+
+    #[test]
+    fn bad_networks_are_rejected() {
+        let list = vec!["10.0.0.0/33".to_owned()];
+        assert!(TrustedProxies::parse(&list, false).is_err());
+
+        let list = vec!["proxy".to_owned()];
+        assert!(TrustedProxies::parse(&list, false).is_err());
+    }
+
+    #[test]
+    fn redirects_keep_host_and_path() {
+        assert_eq!(https_url("example.com", "/ws?a=1", 443), "https://example.com/ws?a=1");
+        assert_eq!(https_url("example.com:80", "/", 8443), "https://example.com:8443/");
+        assert_eq!(https_url("[::1]:80", "/", 443), "https://[::1]/");
+        assert_eq!(https_url("[::1]", "/", 443), "https://[::1]/");
+    }
+}
@@ -8,12 +8,32 @@ use std::fmt::{Display, Formatter};
 #[derive(Debug)]
 pub enum ResErr {
     BadClientData(&'static str),
+    Forbidden(&'static str),
+    NotFound(&'static str),
+    PayloadTooLarge(&'static str),
+    Conflict(&'static str),
+    UnsupportedMediaType(&'static str),
+    PreconditionFailed(&'static str),
+    ChecksumMismatch(&'static str),
+    InsufficientStorage(&'static str),
+    ServiceUnavailable(&'static str),
+    InternalError(&'static str),
 }
 
 impl Display for ResErr {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        match &self {
-            &ResErr::BadClientData(s) => write!(f, "{}", s),
+        match self {
+            ResErr::BadClientData(s) => write!(f, "{}", s),
+            ResErr::Forbidden(s) => write!(f, "{}", s),
+            ResErr::NotFound(s) => write!(f, "{}", s),
+            ResErr::PayloadTooLarge(s) => write!(f, "{}", s),
+            ResErr::Conflict(s) => write!(f, "{}", s),
+            ResErr::UnsupportedMediaType(s) => write!(f, "{}", s),
+            ResErr::PreconditionFailed(s) => write!(f, "{}", s),
+            ResErr::ChecksumMismatch(s) => write!(f, "{}", s),
+            ResErr::InsufficientStorage(s) => write!(f, "{}", s),
+            ResErr::ServiceUnavailable(s) => write!(f, "{}", s),
+            ResErr::InternalError(s) => write!(f, "{}", s),
         }
     }
 }
@@ -28,6 +48,17 @@ impl error::ResponseError for ResErr {
     fn status_code(&self) -> StatusCode {
         match *self {
             ResErr::BadClientData(_) => StatusCode::BAD_REQUEST,
+            ResErr::Forbidden(_) => StatusCode::FORBIDDEN,
+            ResErr::NotFound(_) => StatusCode::NOT_FOUND,
+            ResErr::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ResErr::Conflict(_) => StatusCode::CONFLICT,
+            ResErr::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ResErr::PreconditionFailed(_) => StatusCode::PRECONDITION_FAILED,
+            // tus checksum extension
+            ResErr::ChecksumMismatch(_) => StatusCode::from_u16(460).unwrap_or(StatusCode::BAD_REQUEST),
+            ResErr::InsufficientStorage(_) => StatusCode::INSUFFICIENT_STORAGE,
+            ResErr::ServiceUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ResErr::InternalError(_) => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,91 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use actix::prelude::*;
+
+use crate::backplane::{node_of, NodeId};
+use crate::server::{ChatServer, ReserveResult, ReserveRoom};
+
+/// Chat server shards of this process, each on its own arbiter with a node id of its own.
+/// Messages go to the shard their session or room lives on, shards coordinate the rest
+/// over the backplane.
+#[derive(Debug, Clone)]
+pub struct Router {
+    /// node id of the first shard, the others follow it
+    first: NodeId,
+    shards: Arc<[Addr<ChatServer>]>,
+    /// shard the next session without a client certificate starts looking for a room at
+    next: Arc<AtomicUsize>,
+}
+
+impl Router {
+    pub fn new(first: NodeId, shards: Vec<Addr<ChatServer>>) -> Router {
+        assert!(!shards.is_empty(), "at least one shard");
+
+        Router {
+            first,
+            shards: shards.into(),
+            next: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Shard a session or room lives on, ids of other nodes go to the first one
+    pub fn shard(&self, id: usize) -> &Addr<ChatServer> {
+        &self.shards[index(self.first, self.shards.len(), id)]
+    }
+
+    pub fn shards(&self) -> &[Addr<ChatServer>] {
+        &self.shards
+    }
+
+    /// Reserve the room of a new session, the session lives on the shard of the room.
+    /// Sessions of one client certificate subject share a shard so its session limit holds,
+    /// the others take turns and move on to the next shard when one is full.
+    pub async fn reserve(&self, identity: Option<String>) -> Result<ReserveResult, MailboxError> {
+        let len = self.shards.len();
+        let order: Vec<usize> = match &identity {
+            Some(identity) => {
+                let mut hasher = DefaultHasher::new();
+                identity.hash(&mut hasher);
+                vec![hasher.finish() as usize % len]
+            }
+            None => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed);
+                (0..len).map(|offset| (start + offset) % len).collect()
+            }
+        };
+
+        for index in order {
+            let identity = identity.clone();
+            match self.shards[index].send(ReserveRoom { identity }).await? {
+                ReserveResult::FullQueue => continue,
+                result => return Ok(result),
+            }
+        }
+
+        Ok(ReserveResult::FullQueue)
+    }
+}
+
+/// Index of the shard `id` lives on among `len` shards starting at node `first`
+fn index(first: NodeId, len: usize, id: usize) -> usize {
+    Some(usize::from(node_of(id).wrapping_sub(first)))
+        .filter(|index| *index < len)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::index;
+    use crate::backplane::on_node;
+
+    #[test]
+    fn ids_go_to_the_shard_of_their_node() {
+        assert_eq!(index(0, 1, on_node(7, 0)), 0);
+        assert_eq!(index(4, 4, on_node(7, 6)), 2);
+        assert_eq!(index(4, 4, on_node(7, 8)), 0);
+        assert_eq!(index(4, 4, on_node(7, 3)), 0);
+    }
+}
@@ -1,46 +1,347 @@
-use std::sync::Mutex;
-use std::time::Instant;
+use std::fs;
+use std::io::Write;
+use std::time::{Duration, Instant};
 
 use actix::*;
+use actix_files::NamedFile;
+use actix_web::http::header::{
+    self, ContentDisposition, ContentType, DispositionParam, DispositionType,
+};
 use actix_web::{web, HttpRequest, HttpResponse};
+use futures_util::StreamExt;
+use rand::Rng;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
 
 use actix_web_actors::ws;
 
-use crate::queue;
+use crate::config::Config;
 use crate::reserr::ResErr;
+use crate::router::Router;
 use crate::server;
 use crate::session;
+use crate::tls;
+use crate::uploads::{
+    self, Access, Fetch, Lookup, Release, Reserve, Store, Upload, UploadConfig, UploadStore,
+};
 
 /// Entry point for our websocket route
 pub async fn chat_route(
     req: HttpRequest,
     stream: web::Payload,
-    srv: web::Data<Addr<server::ChatServer>>,
-    queue: web::Data<Mutex<queue::Queue>>,
+    srv: web::Data<Router>,
+    config: web::Data<Config>,
 ) -> Result<HttpResponse, ResErr> {
-    let room;
-    {
-        let mut guard = queue.lock().unwrap();
-        room = guard.reserve().copied();
-    }
+    let identity = req
+        .conn_data::<tls::PeerIdentity>()
+        .map(|identity| identity.0.clone());
+    let room = srv
+        .reserve(identity.clone())
+        .await
+        .map_err(|_| ResErr::InternalError("chat server unavailable"))?;
+
     match room {
-        Some(x) => ws::start(
+        server::ReserveResult::Reserved(x) => ws::start(
             session::WsChatSession {
                 id: 0,
+                token: 0,
                 hb: Instant::now(),
-                room: x.to_owned(),
-                addr: srv.get_ref().clone(),
+                hb_interval: config.heartbeat_interval(),
+                client_timeout: config.client_timeout(),
+                room: x,
+                identity,
+                addr: srv.shard(x).clone(),
+                router: srv.get_ref().clone(),
+                requests: session::Requests::default(),
             },
             &req,
             stream,
         )
         .map_err(|_| {
-            {
-                let mut guard = queue.lock().unwrap();
-                guard.refund(&x);
-            }
+            srv.shard(x).do_send(server::RefundRoom { room: x });
             ResErr::BadClientData("something wrong")
         }),
-        None => Err(ResErr::BadClientData("full queue")),
+        server::ReserveResult::FullQueue => Err(ResErr::BadClientData("full queue")),
+        server::ReserveResult::TooManySessions => Err(ResErr::Forbidden(
+            "too many sessions for this certificate",
+        )),
+        server::ReserveResult::ShuttingDown => {
+            Err(ResErr::ServiceUnavailable("server is shutting down"))
+        }
+    }
+}
+
+/// Scheme of the `Authorization` header uploads and downloads name their session with
+const AUTH_SCHEME: &str = "PeerShare";
+
+/// Session a request acts for, from `Authorization: PeerShare <session>:<token>`.
+/// The token is the `/resume` secret, so it stays out of urls and the access log.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Credentials {
+    pub session: usize,
+    pub token: usize,
+}
+
+impl Credentials {
+    /// `None` when the request carries no or a malformed header
+    pub fn of(req: &HttpRequest) -> Option<Credentials> {
+        let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+        Credentials::parse(value)
+    }
+
+    pub fn required(req: &HttpRequest) -> Result<Credentials, ResErr> {
+        Credentials::of(req).ok_or(ResErr::Forbidden("session credentials required"))
+    }
+
+    fn parse(value: &str) -> Option<Credentials> {
+        let (scheme, credentials) = value.trim().split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case(AUTH_SCHEME) {
+            return None;
+        }
+        let (session, token) = credentials.trim().split_once(':')?;
+
+        Some(Credentials {
+            session: session.parse().ok()?,
+            token: token.parse().ok()?,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+pub struct UploadQuery {
+    room: usize,
+    name: String,
+    access: Option<Access>,
+    /// seconds to keep the file, capped by the server limit
+    ttl: Option<u64>,
+    max_downloads: Option<u32>,
+}
+
+/// Store a file for the members of a room, e.g. the ones that are offline right now
+pub async fn upload_route(
+    req: HttpRequest,
+    mut payload: web::Payload,
+    query: web::Query<UploadQuery>,
+    srv: web::Data<Router>,
+    store: web::Data<Addr<UploadStore>>,
+    config: web::Data<UploadConfig>,
+) -> Result<HttpResponse, ResErr> {
+    let credentials = Credentials::required(&req)?;
+    let query = query.into_inner();
+    if query.name.is_empty() || query.name.len() > 255 || query.name.contains(['/', '\\']) {
+        return Err(ResErr::BadClientData("bad file name"));
+    }
+
+    let authorized = srv
+        .shard(credentials.session)
+        .send(server::Authorize {
+            id: credentials.session,
+            token: credentials.token,
+            room: query.room,
+        })
+        .await
+        .map_err(|_| ResErr::InternalError("chat server unavailable"))?;
+    if !authorized {
+        return Err(ResErr::Forbidden("not a member of the room"));
+    }
+
+    let declared = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok())
+        .and_then(|len| len.parse::<u64>().ok());
+    if declared.is_some_and(|len| len > config.max_size) {
+        return Err(ResErr::PayloadTooLarge("file too large"));
+    }
+
+    // quota is held for the largest the file can get before anything is written
+    let id: u64 = rand::thread_rng().gen();
+    let key: u64 = rand::thread_rng().gen();
+    let limit = declared.unwrap_or(config.max_size);
+    store
+        .send(Reserve {
+            id,
+            uploader: credentials.session,
+            size: limit,
+        })
+        .await
+        .map_err(|_| ResErr::InternalError("upload store unavailable"))?
+        .map_err(|_| ResErr::InsufficientStorage("upload quota exceeded"))?;
+    let mut reservation = Reservation {
+        store: store.get_ref().clone(),
+        id,
+        stored: false,
+    };
+
+    let path = config.dir.join(id.to_string());
+    let part = uploads::part_path(&config.dir, id);
+    let mut file = blocking({
+        let part = part.clone();
+        move || fs::File::create(part)
+    })
+    .await?;
+    let mut hasher = Sha256::new();
+    let mut size: u64 = 0;
+
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(|_| ResErr::BadClientData("upload interrupted"))?;
+        size += chunk.len() as u64;
+        if size > limit {
+            return Err(ResErr::PayloadTooLarge("file too large"));
+        }
+        hasher.update(&chunk);
+        file = blocking(move || file.write_all(&chunk).map(|_| file)).await?;
+    }
+    drop(file);
+    if declared.is_some_and(|len| size < len) {
+        return Err(ResErr::BadClientData("upload interrupted"));
+    }
+    blocking({
+        let path = path.clone();
+        move || fs::rename(part, path)
+    })
+    .await?;
+
+    let access = query.access.unwrap_or(Access::Room);
+    let ttl = query
+        .ttl
+        .map_or(config.ttl, Duration::from_secs)
+        .min(config.ttl);
+    let upload = Upload {
+        id,
+        room: query.room,
+        uploader: credentials.session,
+        name: query.name,
+        size,
+        hash: format!("sha256:{:x}", hasher.finalize()),
+        access,
+        link: uploads::link(id, access, key),
+        downloads_left: query
+            .max_downloads
+            .unwrap_or(config.max_downloads)
+            .clamp(1, config.max_downloads.max(1)),
+        key,
+        expires: Instant::now() + ttl,
+    };
+
+    let json = serde_json::to_string(&upload).unwrap_or_default();
+    let stored = store
+        .send(Store(upload))
+        .await
+        .map_err(|_| ResErr::InternalError("upload store unavailable"))?;
+    if stored.is_err() {
+        let _ = blocking(move || fs::remove_file(path)).await;
+        return Err(ResErr::InsufficientStorage("upload quota exceeded"));
+    }
+    reservation.stored = true;
+    srv.shard(query.room).do_send(server::UploadShared {
+        id: credentials.session,
+        room: query.room,
+        upload: json.clone(),
+    });
+
+    Ok(HttpResponse::Created()
+        .content_type(ContentType::json())
+        .body(json))
+}
+
+/// Gives the quota held for an upload back when the request ends without storing it,
+/// also when the client goes away midway and the handler is dropped
+struct Reservation {
+    store: Addr<UploadStore>,
+    id: u64,
+    stored: bool,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        if !self.stored {
+            self.store.do_send(Release { id: self.id });
+        }
+    }
+}
+
+/// Run file I/O on the blocking thread pool instead of the worker serving requests
+pub async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> std::io::Result<T> + Send + 'static,
+) -> Result<T, ResErr> {
+    web::block(f)
+        .await
+        .ok()
+        .and_then(Result::ok)
+        .ok_or(ResErr::InternalError("cant store file"))
+}
+
+#[derive(Deserialize)]
+pub struct DownloadQuery {
+    key: Option<u64>,
+}
+
+/// Download a stored file, members authenticate with their session and token, links carry a key
+pub async fn download_route(
+    req: HttpRequest,
+    id: web::Path<u64>,
+    query: web::Query<DownloadQuery>,
+    srv: web::Data<Router>,
+    store: web::Data<Addr<UploadStore>>,
+    config: web::Data<UploadConfig>,
+) -> Result<HttpResponse, ResErr> {
+    let id = id.into_inner();
+    let upload = store
+        .send(Lookup { id })
+        .await
+        .map_err(|_| ResErr::InternalError("upload store unavailable"))?
+        .ok_or(ResErr::NotFound("file not found"))?;
+
+    let authorized = match (upload.access, Credentials::of(&req)) {
+        (Access::Link, _) => query.key == Some(upload.key),
+        (Access::Room, Some(credentials)) => srv
+            .shard(credentials.session)
+            .send(server::Authorize {
+                id: credentials.session,
+                token: credentials.token,
+                room: upload.room,
+            })
+            .await
+            .map_err(|_| ResErr::InternalError("chat server unavailable"))?,
+        (Access::Room, None) => false,
+    };
+    if !authorized {
+        return Err(ResErr::Forbidden("not allowed to download this file"));
+    }
+
+    // counts the download, the file may have been used up meanwhile
+    let upload = store
+        .send(Fetch { id })
+        .await
+        .map_err(|_| ResErr::InternalError("upload store unavailable"))?
+        .ok_or(ResErr::NotFound("file not found"))?;
+
+    let file = NamedFile::open(upload.path(&config.dir))
+        .map_err(|_| ResErr::NotFound("file not found"))?
+        .set_content_disposition(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(upload.name)],
+        });
+
+    Ok(file.into_response(&req))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Credentials;
+
+    #[test]
+    fn credentials_come_from_the_peershare_scheme_only() {
+        let credentials = Credentials {
+            session: 42,
+            token: 7,
+        };
+        assert_eq!(Credentials::parse("PeerShare 42:7"), Some(credentials));
+        assert!(Credentials::parse("peershare 42:7").is_some());
+
+        assert_eq!(Credentials::parse("Bearer 42:7"), None);
+        assert_eq!(Credentials::parse("PeerShare 42"), None);
+        assert_eq!(Credentials::parse("PeerShare 42:x"), None);
     }
 }
@@ -1,31 +1,113 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::{Duration, Instant};
 
 use actix::prelude::*;
-use actix_web::web::Data;
+use futures_util::future::ready;
 use rand::{self, rngs::ThreadRng, Rng};
-use std::sync::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
 
-use crate::queue::Queue;
+use crate::allocator::RoomAllocator;
+use crate::backplane::{node_of, on_node, Backplane, Envelope, NodeId, Remote};
+use crate::history::{instant_at, unix_millis_at, Entry, History, Stamp};
+use crate::snapshot::{EntrySnapshot, RoomSnapshot, SessionSnapshot, Snapshot};
+use crate::store::RoomStore;
+use crate::transfer::{Offer, Transfer, Update};
 
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct Message(pub String);
 
+/// Id of a newly registered session and the secret it can resume with after a reconnect
+#[derive(Debug, Clone, Copy)]
+pub struct Connected {
+    pub id: usize,
+    pub token: usize,
+}
+
+/// The server is shutting down, the session is told to come back in `drain` and closed
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct GoingAway {
+    pub drain: Duration,
+}
+
 #[derive(Message)]
-#[rtype(usize)]
+#[rtype(Connected)]
 pub struct Connect {
     pub addr: Recipient<Message>,
+    pub going_away: Recipient<GoingAway>,
     pub room: usize,
+    pub identity: Option<String>,
+}
+
+pub enum ResumeResult {
+    Resumed,
+    /// The session is unknown, still connected or the token does not match
+    BadToken,
+}
+
+/// Take over a session that lost its socket less than the reconnect grace period ago.
+/// The session `id` registered for the new socket is dropped in favour of `resume_id`.
+pub struct Resume {
+    pub id: usize,
+    pub resume_id: usize,
+    pub token: usize,
+    pub addr: Recipient<Message>,
+    pub going_away: Recipient<GoingAway>,
+    /// Client certificate subject of the new connection, must match the one of the session
+    pub identity: Option<String>,
+}
+
+impl actix::Message for Resume {
+    type Result = ResumeResult;
 }
 
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct Disconnect {
     pub id: usize,
+}
+
+/// Short lived activity of a session shown to the other members of a room
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Activity {
+    Typing,
+    PickingFiles,
+    Uploading,
+}
+
+impl Activity {
+    pub fn parse(name: &str) -> Option<Activity> {
+        match name {
+            "typing" => Some(Activity::Typing),
+            "picking_files" => Some(Activity::PickingFiles),
+            "uploading" => Some(Activity::Uploading),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Activity::Typing => "typing",
+            Activity::PickingFiles => "picking_files",
+            Activity::Uploading => "uploading",
+        }
+    }
+}
+
+/// Set or clear the ephemeral state of a session in a room.
+/// It never enters history and is dropped unless refreshed within a few seconds.
+#[derive(Debug, Message, Serialize, Deserialize)]
+#[rtype(result = "()")]
+pub struct Ephemeral {
+    pub id: usize,
     pub room: usize,
+    /// `None` clears the state
+    pub activity: Option<Activity>,
 }
 
-#[derive(Message)]
+#[derive(Debug, Message, Serialize, Deserialize)]
 #[rtype(result = "()")]
 pub struct ClientMessage {
     /// Id of the client session
@@ -36,13 +118,143 @@ pub struct ClientMessage {
     pub room: usize,
 }
 
-pub struct ListRooms;
+/// Recipient confirms it received a direct message
+#[derive(Debug, Message, Serialize, Deserialize)]
+#[rtype(result = "()")]
+pub struct Ack {
+    pub id: usize,
+    pub message_id: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum TransferResult {
+    Offered(u64),
+    Updated,
+    NotMember,
+    IdDontExist,
+    NotFound,
+    /// The session may not make this update in the transfer's current state
+    NotAllowed,
+}
+
+/// Announce files to a member of the room
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TransferOffer {
+    pub id: usize,
+    pub room: usize,
+    pub offer: Offer,
+}
+
+impl actix::Message for TransferOffer {
+    type Result = TransferResult;
+}
+
+/// Accept, reject, report progress on, complete or cancel a transfer
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TransferUpdate {
+    pub id: usize,
+    pub room: usize,
+    pub transfer: u64,
+    pub update: Update,
+}
+
+impl actix::Message for TransferUpdate {
+    type Result = TransferResult;
+}
+
+/// Replay the events of a room after a sequence number
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Since {
+    pub id: usize,
+    pub room: usize,
+    pub seq: u64,
+}
+
+impl actix::Message for Since {
+    /// Event lines and the latest sequence number of the room,
+    /// `None` when the session is not a member of the room
+    type Result = Option<(Vec<String>, u64)>;
+}
+
+/// Check an HTTP request made on behalf of a session that is a member of the room
+pub struct Authorize {
+    pub id: usize,
+    pub token: usize,
+    pub room: usize,
+}
+
+impl actix::Message for Authorize {
+    type Result = bool;
+}
+
+/// Tell the room a file was stored for its members
+#[derive(Debug, Message, Serialize, Deserialize)]
+#[rtype(result = "()")]
+pub struct UploadShared {
+    pub id: usize,
+    pub room: usize,
+    /// upload description sent to clients
+    pub upload: String,
+}
+
+pub enum ReserveResult {
+    Reserved(usize),
+    FullQueue,
+    /// The client certificate subject has as many sessions open as it may
+    TooManySessions,
+    ShuttingDown,
+}
+
+/// Reserve a room for a new session
+pub struct ReserveRoom {
+    /// Client certificate subject of the connection
+    pub identity: Option<String>,
+}
+
+impl actix::Message for ReserveRoom {
+    type Result = ReserveResult;
+}
+
+/// Stop taking new sessions and send every connected one away
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Shutdown {
+    /// How long clients are told the server takes to go away
+    pub drain: Duration,
+}
+
+/// Rooms and sessions as they are now, to restore after a restart
+pub struct TakeSnapshot;
+
+impl actix::Message for TakeSnapshot {
+    type Result = Snapshot;
+}
+
+/// Write what changed to the store right away
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Save;
+
+/// Give back a room that was reserved but never used
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct RefundRoom {
+    pub room: usize,
+}
+
+/// Rooms the session is a member of
+pub struct ListRooms {
+    pub id: usize,
+}
 
 impl actix::Message for ListRooms {
     type Result = Vec<usize>;
 }
 
+/// Key of a room, only handed out to its members
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Room {
+    pub id: usize,
     pub name: usize,
 }
 
@@ -50,13 +262,15 @@ impl actix::Message for Room {
     type Result = Option<usize>;
 }
 
-#[derive(Message)]
+#[derive(Debug, Message, Serialize, Deserialize)]
 #[rtype(InviteResult)]
 pub enum InviteResult {
     Asked,
     RoomDontExist,
+    NotMember,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Invite {
     pub id: usize,
     pub room: usize,
@@ -67,40 +281,68 @@ impl actix::Message for Invite {
     type Result = InviteResult;
 }
 
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Members {
+    pub id: usize,
     pub room: usize,
 }
 
 impl actix::Message for Members {
-    type Result = Vec<usize>;
+    /// `None` when the session is not a member of the room
+    type Result = Option<Vec<usize>>;
+}
+
+/// Client certificate subjects of the members of a room
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Identities {
+    pub id: usize,
+    pub room: usize,
 }
 
+impl actix::Message for Identities {
+    /// `None` when the session is not a member of the room
+    type Result = Option<BTreeMap<usize, String>>;
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Direct {
     pub room: usize,
     pub id_to: usize,
     pub id_from: usize,
     pub mess: String,
+    /// The recipient has to confirm the message with `/ack`
+    pub ack: bool,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
 pub enum DirectResult {
     Send,
+    /// Waiting for the recipient to acknowledge the message with this id
+    Pending(u64),
     IdDontExist,
+    NotMember,
 }
 
 impl actix::Message for Direct {
     type Result = DirectResult;
 }
 
-#[derive(Message)]
+#[derive(Debug, Message, Serialize, Deserialize)]
 #[rtype(InviteResult)]
 pub enum SendRoomKeyResult {
     Send,
     RoomDontExist,
+    NotMember,
+    /// The target never asked to be let into the room, or its invite expired
+    NoInvite,
 }
 
+/// Share the key of `room` with session `id`, which must have an outstanding invite
+#[derive(Debug, Serialize, Deserialize)]
 pub struct SendRoomKey {
+    /// Session sharing the key, must be a member of the room
+    pub sender: usize,
     pub room: usize,
-    pub from_room: usize,
     pub id: usize,
 }
 
@@ -108,332 +350,2718 @@ impl actix::Message for SendRoomKey {
     type Result = SendRoomKeyResult;
 }
 
-#[derive(Message)]
+#[derive(Debug, Message, Serialize, Deserialize)]
 #[rtype(JoinResult)]
 pub enum JoinResult {
     Joined(usize),
     RoomDontExist,
     BadKey,
-    FullRoom
+    FullRoom,
+    AlreadyMember,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Join {
     pub id: usize,
 
     pub name: usize,
 
     pub key: usize,
-
-    pub room: usize,
+    /// Client certificate subject of the session, kept for `/who` when the room lives elsewhere
+    pub identity: Option<String>,
 }
 
 impl actix::Message for Join {
     type Result = JoinResult;
 }
 
-#[derive(Debug)]
-pub struct ChatServer {
-    sessions: HashMap<usize, Recipient<Message>>,
-    rooms: HashMap<usize, HashSet<usize>>,
-    queue: Data<Mutex<Queue>>,
-    keys: HashMap<usize, usize>,
-    rng: ThreadRng,
+#[derive(Message)]
+#[rtype(LeaveResult)]
+pub enum LeaveResult {
+    /// Left the room and got a new private room with its key
+    Left { room: usize, key: usize },
+    /// Left the room but there is no free room to move to
+    FullQueue,
+    NotMember,
 }
 
-impl ChatServer {
-    pub fn new(queue: Data<Mutex<Queue>>) -> ChatServer {
-        let rooms = HashMap::new();
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Leave {
+    pub id: usize,
+    pub room: usize,
+}
 
-        ChatServer {
-            sessions: HashMap::new(),
-            rooms,
-            queue,
-            keys: HashMap::new(),
-            rng: rand::thread_rng(),
-        }
-    }
+impl actix::Message for Leave {
+    type Result = LeaveResult;
 }
 
-impl ChatServer {
-    fn send_message(&self, room: &usize, message: &str, skip_id: usize) {
-        if let Some(sessions) = self.rooms.get(room) {
-            for id in sessions {
-                if *id != skip_id {
-                    if let Some(addr) = self.sessions.get(id) {
-                        addr.do_send(Message(message.to_owned()));
-                    }
-                }
-            }
-        }
-    }
+#[derive(Debug, Serialize, Deserialize)]
+pub enum RotateResult {
+    Rotated,
+    NotOwner,
+    RoomDontExist,
 }
 
-impl ChatServer {
-    fn send_message_to_id(&self, room: &usize, message: &str, id: usize) {
-        if let Some(sessions) = self.rooms.get(room) {
-            for user_id in sessions {
-                if *user_id == id {
-                    if let Some(addr) = self.sessions.get(user_id) {
-                        addr.do_send(Message(message.to_owned()));
-                    }
-                }
-            }
-        }
-    }
+/// Replace the room key, only the room owner may do it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RotateKey {
+    pub id: usize,
+    pub room: usize,
 }
 
-impl Actor for ChatServer {
-    type Context = Context<Self>;
+impl actix::Message for RotateKey {
+    type Result = RotateResult;
 }
 
-impl Handler<Connect> for ChatServer {
-    type Result = usize;
+/// Change when the room key is rotated automatically
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SetRotation {
+    pub id: usize,
+    pub room: usize,
+    pub policy: RotationPolicy,
+}
 
-    fn handle(&mut self, msg: Connect, _: &mut Context<Self>) -> Self::Result {
-        self.rooms.insert(msg.room.to_owned(), HashSet::new());
+/// Result of room settings only the owner may change
+#[derive(Debug, Serialize, Deserialize)]
+pub enum OwnerResult {
+    Set,
+    NotOwner,
+    RoomDontExist,
+}
 
-        // register session with random id
-        let id = self.rng.gen::<usize>();
-        self.sessions.insert(id, msg.addr);
+impl actix::Message for SetRotation {
+    type Result = OwnerResult;
+}
 
-        // auto join session to main room
-        self.rooms
-            .entry(msg.room)
-            .or_insert_with(HashSet::new)
-            .insert(id);
+/// Lower how many and how old chat messages the room keeps
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SetHistoryLimits {
+    pub id: usize,
+    pub room: usize,
+    pub max_len: usize,
+    pub max_age: Duration,
+}
 
-        self.keys.insert(msg.room, self.rng.gen());
+impl actix::Message for SetHistoryLimits {
+    type Result = OwnerResult;
+}
 
-        // send id back
-        id
-    }
+/// Commands a node forwards to the node a room or session lives on
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Request {
+    Message(ClientMessage),
+    Ephemeral(Ephemeral),
+    Ack(Ack),
+    Members(Members),
+    Room(Room),
+    Direct(Direct),
+    Invite(Invite),
+    SendRoomKey(SendRoomKey),
+    Join(Join),
+    Leave(Leave),
+    Since(Since),
+    RotateKey(RotateKey),
+    SetRotation(SetRotation),
+    SetHistoryLimits(SetHistoryLimits),
+    TransferOffer(TransferOffer),
+    TransferUpdate(TransferUpdate),
+    Identities(Identities),
+    UploadShared(UploadShared),
+    /// Remove a session whose socket resumed another one
+    Drop(usize),
+    /// Send a line to every member of the room but `skip`
+    Announce {
+        room: usize,
+        line: String,
+        skip: usize,
+    },
 }
 
-/// Handler for Disconnect message.
-impl Handler<Disconnect> for ChatServer {
-    type Result = ();
+/// Answers to the requests that have one
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Reply {
+    Members(Option<Vec<usize>>),
+    Room(Option<usize>),
+    Direct(DirectResult),
+    Invite(InviteResult),
+    SendRoomKey(SendRoomKeyResult),
+    Join(JoinResult),
+    Left(bool),
+    Since(Option<(Vec<String>, u64)>),
+    Rotate(RotateResult),
+    Owner(OwnerResult),
+    Transfer(TransferResult),
+    Identities(Option<BTreeMap<usize, String>>),
+}
 
-    fn handle(&mut self, msg: Disconnect, _: &mut Context<Self>) {
-        let Disconnect { id, room } = msg;
+/// How often changed rooms and sessions are written to the store
+const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often rooms are checked for expiry and timed key rotation
+const HOUSEKEEPING_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often changed ephemeral state is sent to a room, at most
+const EPHEMERAL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long ephemeral state lives unless the session refreshes it
+const EPHEMERAL_TTL: Duration = Duration::from_secs(5);
+
+/// How long the recipient of an acknowledged direct message has to confirm it
+const ACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a forwarded command waits for the node of its room to answer
+const REMOTE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long rooms may live, `None` means no limit
+#[derive(Debug, Clone)]
+pub struct RoomLimits {
+    /// Most members of a room
+    pub capacity: usize,
+    /// Time since the room was created
+    pub max_age: Option<Duration>,
+    /// Time since the last message or signaling command in the room
+    pub idle_timeout: Option<Duration>,
+    /// How long before expiry members are warned
+    pub warning: Duration,
+    /// Most chat messages a room keeps for late joiners, owners may only lower it
+    pub history_len: usize,
+    /// How long chat messages are kept, owners may only lower it
+    pub history_age: Duration,
+}
 
-        // remove address
-        if self.sessions.remove(&id).is_some() {
-            // remove session from room
-            if let Some(sessions) = self.rooms.get_mut(&room) {
-                sessions.remove(&id);
-
-                if sessions.is_empty() {
-                    {
-                        let mut guard = self.queue.lock().unwrap();
-                        guard.refund(&room);
-                    }
+/// When a room key is replaced without the owner asking for it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RotationPolicy {
+    Manual,
+    OnJoin,
+    Every(Duration),
+}
 
-                    self.rooms.remove(&room);
-                    self.keys.remove(&room);
-                }
-            }
-        }
+#[derive(Debug)]
+struct RoomState {
+    members: HashSet<usize>,
+    /// session that may rotate the key, passed on when it leaves
+    owner: usize,
+    key: usize,
+    rotation: RotationPolicy,
+    last_rotation: Instant,
+    /// sessions that asked to be let in with the current key
+    invites: HashSet<usize>,
+    /// recent events replayed to sessions that join or ask for them
+    history: History,
+    /// sequence number of the last stamped event
+    seq: u64,
+    /// activity of members and when it runs out
+    ephemeral: HashMap<usize, (Activity, Instant)>,
+    /// ephemeral state changed since it was last sent to the room
+    ephemeral_dirty: bool,
+    /// file transfers that are not over yet, by transfer id
+    transfers: HashMap<u64, Transfer>,
+    /// client certificate subjects of members of other nodes, their node knows the rest
+    identities: HashMap<usize, String>,
+    created: Instant,
+    last_activity: Instant,
+    /// members were already told that the room is about to expire
+    warned: bool,
+}
 
-        let mut ids_vec = Vec::new();
+impl RoomState {
+    /// When the room expires under the given limits, `None` when it never does
+    fn expires_at(&self, limits: &RoomLimits) -> Option<Instant> {
+        let by_age = limits.max_age.map(|age| self.created + age);
+        let by_idle = limits.idle_timeout.map(|idle| self.last_activity + idle);
 
-        if let Some(ids) = self.rooms.get(&room) {
-            for room_id in ids {
-                ids_vec.push(room_id.to_owned());
-            }
+        match (by_age, by_idle) {
+            (Some(age), Some(idle)) => Some(age.min(idle)),
+            (age, idle) => age.or(idle),
         }
-
-        // send message to other users
-        self.send_message(&room, &format!("/members {:?}", ids_vec), 0);
     }
 }
 
-impl Handler<ClientMessage> for ChatServer {
-    type Result = ();
+/// Room key handed to an invited session
+#[derive(Debug)]
+pub struct RoomKeyShare {
+    pub room: usize,
+    pub stamp: Stamp,
+    /// Member that shared the key
+    pub from: usize,
+    pub key: usize,
+}
 
-    fn handle(&mut self, msg: ClientMessage, _: &mut Context<Self>) {
-        self.send_message(
-            &msg.room,
-            &format!("/message {} {}", msg.id, msg.msg),
-            msg.id,
-        );
+impl std::fmt::Display for RoomKeyShare {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "/send {} {} {} {}", self.room, self.stamp, self.from, self.key)
     }
 }
 
-impl Handler<ListRooms> for ChatServer {
-    type Result = MessageResult<ListRooms>;
+/// Direct message that waits for the recipient's `/ack`
+#[derive(Debug)]
+struct PendingAck {
+    room: usize,
+    sender: usize,
+    recipient: usize,
+    /// `None` while the message is queued for a reconnecting recipient
+    deadline: Option<Instant>,
+}
 
-    fn handle(&mut self, _: ListRooms, _: &mut Context<Self>) -> Self::Result {
-        let mut rooms = Vec::new();
+#[derive(Debug)]
+pub struct ChatServer {
+    sessions: HashMap<usize, Recipient<Message>>,
+    /// where connected sessions are told that the server shuts down
+    going_away: HashMap<usize, Recipient<GoingAway>>,
+    /// set once the server shuts down, to how long it takes
+    draining: Option<Duration>,
+    /// secret each session can resume with
+    tokens: HashMap<usize, usize>,
+    /// client certificate subject of sessions that presented one
+    identities: HashMap<usize, String>,
+    /// most sessions one subject may have connected
+    identity_limit: Option<usize>,
+    /// sessions that lost their socket and until when they may resume
+    detached: HashMap<usize, Instant>,
+    /// direct messages queued for detached sessions, with the id of acknowledged ones
+    outbox: HashMap<usize, Vec<(String, Option<u64>)>>,
+    /// acknowledged direct messages by message id
+    pending_acks: HashMap<u64, PendingAck>,
+    reconnect_grace: Duration,
+    /// rooms every session is a member of
+    memberships: HashMap<usize, HashSet<usize>>,
+    rooms: HashMap<usize, RoomState>,
+    queue: RoomAllocator,
+    limits: RoomLimits,
+    /// where rooms are kept across restarts
+    store: Option<Box<dyn RoomStore>>,
+    /// reaches the other nodes, whose sessions and rooms have their node id in them
+    backplane: Box<dyn Backplane>,
+    node: NodeId,
+    /// forwarded commands waiting for an answer, by correlation id
+    waiting: HashMap<u64, oneshot::Sender<Reply>>,
+    last_correlation: u64,
+    /// acknowledged direct messages from rooms of other nodes, where the `/ack`
+    /// goes and until when
+    remote_acks: HashMap<u64, (NodeId, Instant)>,
+    /// rooms and sessions changed since they were last saved
+    unsaved_rooms: HashSet<usize>,
+    unsaved_sessions: HashSet<usize>,
+    rng: ThreadRng,
+}
 
-        for key in self.rooms.keys() {
-            rooms.push(key.to_owned())
-        }
+impl ChatServer {
+    pub fn new(
+        queue: RoomAllocator,
+        limits: RoomLimits,
+        reconnect_grace: Duration,
+        identity_limit: Option<usize>,
+        backplane: Box<dyn Backplane>,
+    ) -> ChatServer {
+        let rooms = HashMap::new();
 
-        MessageResult(rooms)
+        ChatServer {
+            sessions: HashMap::new(),
+            going_away: HashMap::new(),
+            draining: None,
+            tokens: HashMap::new(),
+            identities: HashMap::new(),
+            identity_limit,
+            detached: HashMap::new(),
+            outbox: HashMap::new(),
+            pending_acks: HashMap::new(),
+            reconnect_grace,
+            memberships: HashMap::new(),
+            rooms,
+            queue,
+            limits,
+            store: None,
+            node: backplane.node(),
+            backplane,
+            waiting: HashMap::new(),
+            last_correlation: 0,
+            remote_acks: HashMap::new(),
+            unsaved_rooms: HashSet::new(),
+            unsaved_sessions: HashSet::new(),
+            rng: rand::thread_rng(),
+        }
     }
 }
 
-impl Handler<Members> for ChatServer {
-    type Result = MessageResult<Members>;
-
-    fn handle(&mut self, mem: Members, _: &mut Context<Self>) -> Self::Result {
-        let mut ids_vec = Vec::new();
+impl ChatServer {
+    /// Keep rooms in `store` from now on, the ones it holds are brought back with `restore`
+    pub fn persist(&mut self, store: Box<dyn RoomStore>) {
+        self.store = Some(store);
+    }
+}
 
-        if let Some(ids) = self.rooms.get(&mem.room) {
-            for id in ids {
-                ids_vec.push(id.to_owned())
-            }
+impl ChatServer {
+    /// Note that a room changed, it is written with the next save
+    fn changed(&mut self, room: usize) {
+        if self.store.is_some() {
+            self.unsaved_rooms.insert(room);
         }
-
-        MessageResult(ids_vec)
     }
-}
 
-impl Handler<Direct> for ChatServer {
-    type Result = MessageResult<Direct>;
+    fn session_changed(&mut self, id: usize) {
+        if self.store.is_some() {
+            self.unsaved_sessions.insert(id);
+        }
+    }
 
-    fn handle(&mut self, mess: Direct, _: &mut Context<Self>) -> Self::Result {
-        let mut idisinroom = false;
+    /// Write changed rooms and sessions to the store and remove the ones that are gone
+    fn save(&mut self) {
+        let Some(mut store) = self.store.take() else {
+            return;
+        };
 
-        if let Some(ids) = self.rooms.get(&mess.room) {
-            for id in ids {
-                if id == &mess.id_to {
-                    idisinroom = true;
-                }
+        for id in std::mem::take(&mut self.unsaved_sessions) {
+            let saved = match self.session_snapshot(id) {
+                Some(session) => store.save_session(&session),
+                None => store.remove_session(id),
+            };
+            if let Err(err) = saved {
+                log::error!("cant save session {}: {}", id, err);
             }
         }
-
-        if idisinroom {
-            self.send_message_to_id(
-                &mess.room,
-                &format!("/direct_message {} {}", mess.id_from, mess.mess),
-                mess.id_to,
-            );
-            return MessageResult(DirectResult::Send);
+        for room in std::mem::take(&mut self.unsaved_rooms) {
+            let saved = match self.room_snapshot(room) {
+                Some(state) => store.save_room(&state),
+                None => store.remove_room(room),
+            };
+            if let Err(err) = saved {
+                log::error!("cant save room {}: {}", room, err);
+            }
         }
 
-        MessageResult(DirectResult::IdDontExist)
+        self.store = Some(store);
     }
 }
 
-impl Handler<Room> for ChatServer {
-    type Result = MessageResult<Room>;
+impl ChatServer {
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            sessions: self.tokens.keys().filter_map(|id| self.session_snapshot(*id)).collect(),
+            rooms: self.rooms.keys().filter_map(|room| self.room_snapshot(*room)).collect(),
+        }
+    }
 
-    fn handle(&mut self, room: Room, _: &mut Self::Context) -> Self::Result {
-        MessageResult(self.keys.get(&room.name).map(|x| x.clone()))
+    fn session_snapshot(&self, id: usize) -> Option<SessionSnapshot> {
+        Some(SessionSnapshot {
+            id,
+            token: *self.tokens.get(&id)?,
+            identity: self.identities.get(&id).cloned(),
+            rooms: self
+                .memberships
+                .get(&id)
+                .map(|rooms| rooms.iter().copied().collect())
+                .unwrap_or_default(),
+        })
     }
-}
 
-impl Handler<Invite> for ChatServer {
-    type Result = MessageResult<Invite>;
+    fn room_snapshot(&self, room: usize) -> Option<RoomSnapshot> {
+        let state = self.rooms.get(&room)?;
+
+        Some(RoomSnapshot {
+            id: room,
+            key: state.key,
+            owner: state.owner,
+            members: state.members.iter().copied().collect(),
+            invites: state.invites.iter().copied().collect(),
+            rotation: match state.rotation {
+                RotationPolicy::Manual => "manual".to_owned(),
+                RotationPolicy::OnJoin => "join".to_owned(),
+                RotationPolicy::Every(period) => period.as_secs().to_string(),
+            },
+            seq: state.seq,
+            history_len: state.history.max_len,
+            history_age: state.history.max_age.as_secs(),
+            history: state
+                .history
+                .entries()
+                .map(|entry| EntrySnapshot {
+                    seq: entry.stamp.seq,
+                    id: entry.stamp.id,
+                    timestamp: entry.stamp.timestamp,
+                    event: entry.event.to_owned(),
+                    sender: entry.sender,
+                    recipient: entry.recipient,
+                    text: entry.text.clone(),
+                })
+                .collect(),
+            identities: state.identities.clone(),
+            created: unix_millis_at(state.created),
+            last_activity: unix_millis_at(state.last_activity),
+        })
+    }
 
-    fn handle(&mut self, data: Invite, _: &mut Self::Context) -> Self::Result {
-        if !self.rooms.contains_key(&data.room) {
-            return MessageResult(InviteResult::RoomDontExist);
+    /// Bring back the rooms of a snapshot. Their sessions wait to be resumed
+    /// for the reconnect grace period, as if their sockets had just closed.
+    pub fn restore(&mut self, snapshot: Snapshot) {
+        let until = Instant::now() + self.reconnect_grace;
+        for session in snapshot.sessions {
+            self.tokens.insert(session.id, session.token);
+            if let Some(identity) = session.identity {
+                self.identities.insert(session.id, identity);
+            }
+            self.detached.insert(session.id, until);
+            // rooms of this node are rebuilt from their members below
+            let remote: HashSet<usize> = session
+                .rooms
+                .into_iter()
+                .filter(|room| node_of(*room) != self.node)
+                .collect();
+            if !remote.is_empty() {
+                self.memberships.insert(session.id, remote);
+            }
         }
 
-        self.send_message(
-            &data.room,
-            &format!("/invite {} {}", &data.from_room, &data.id),
-            0,
-        );
-
-        MessageResult(InviteResult::Asked)
-    }
-}
+        for room in snapshot.rooms {
+            // sessions of other nodes are kept, their node still knows them
+            let members: HashSet<usize> = room
+                .members
+                .into_iter()
+                .filter(|id| self.remote(*id).is_some() || self.tokens.contains_key(id))
+                .collect();
+            // the queue may have shrunk since the snapshot was taken
+            if members.is_empty() || !self.queue.claim(room.id) {
+                log::warn!("not restoring room {}", room.id);
+                self.changed(room.id);
+                continue;
+            }
 
-impl Handler<SendRoomKey> for ChatServer {
-    type Result = MessageResult<SendRoomKey>;
+            let rotation = match room.rotation.as_str() {
+                "join" => RotationPolicy::OnJoin,
+                secs => match secs.parse::<u64>() {
+                    Ok(secs) if secs > 0 => RotationPolicy::Every(Duration::from_secs(secs)),
+                    _ => RotationPolicy::Manual,
+                },
+            };
+            let mut history = History::new(
+                room.history_len.min(self.limits.history_len),
+                Duration::from_secs(room.history_age).min(self.limits.history_age),
+            );
+            for entry in room.history {
+                let stamp = Stamp {
+                    seq: entry.seq,
+                    id: entry.id,
+                    timestamp: entry.timestamp,
+                };
+                if let Some(entry) =
+                    Entry::restore(stamp, &entry.event, entry.sender, entry.recipient, &entry.text)
+                {
+                    history.push(entry);
+                }
+            }
 
-    fn handle(&mut self, data: SendRoomKey, _: &mut Self::Context) -> Self::Result {
-        if !self.rooms.contains_key(&data.room) {
-            return MessageResult(SendRoomKeyResult::RoomDontExist);
+            for id in members.iter().filter(|id| node_of(**id) == self.node) {
+                self.memberships.entry(*id).or_default().insert(room.id);
+            }
+            let owner = match members.contains(&room.owner) {
+                true => room.owner,
+                false => members.iter().copied().min().unwrap_or_default(),
+            };
+            self.rooms.insert(
+                room.id,
+                RoomState {
+                    invites: room
+                        .invites
+                        .into_iter()
+                        .filter(|id| self.remote(*id).is_some() || self.tokens.contains_key(id))
+                        .collect(),
+                    members,
+                    owner,
+                    key: room.key,
+                    rotation,
+                    last_rotation: Instant::now(),
+                    history,
+                    seq: room.seq,
+                    ephemeral: HashMap::new(),
+                    ephemeral_dirty: false,
+                    transfers: HashMap::new(),
+                    identities: room
+                        .identities
+                        .into_iter()
+                        .filter(|(id, _)| self.remote(*id).is_some())
+                        .collect(),
+                    created: instant_at(room.created),
+                    last_activity: instant_at(room.last_activity),
+                    warned: false,
+                },
+            );
         }
-        if !self.keys.contains_key(&data.room) {
-            return MessageResult(SendRoomKeyResult::RoomDontExist);
+
+        // sessions without a room left have nothing to come back to
+        let empty: Vec<usize> = self
+            .tokens
+            .keys()
+            .filter(|id| !self.memberships.contains_key(*id))
+            .copied()
+            .collect();
+        for id in empty {
+            self.drop_session(id);
         }
 
-        self.send_message_to_id(
-            &data.room,
-            &format!(
-                "/send {} {:?}",
-                &data.from_room,
-                self.keys.get(&data.from_room)
-            ),
-            data.id,
+        log::info!(
+            "node {} restored {} rooms of {} sessions",
+            self.node,
+            self.rooms.len(),
+            self.tokens.len()
         );
-
-        MessageResult(SendRoomKeyResult::Send)
     }
 }
 
-impl Handler<Join> for ChatServer {
-    type Result = MessageResult<Join>;
-
-    fn handle(&mut self, msg: Join, _: &mut Context<Self>) -> Self::Result {
+impl ChatServer {
+    fn send_message(&self, room: &usize, message: &str, skip_id: usize) {
+        if let Some(state) = self.rooms.get(room) {
+            for id in &state.members {
+                if *id != skip_id {
+                    self.send_to_session(*id, message);
+                }
+            }
+        }
+    }
+}
+
+impl ChatServer {
+    fn send_to_session(&self, id: usize, message: &str) {
+        if let Some(node) = self.remote(id) {
+            let deliver = Envelope::Deliver {
+                session: id,
+                line: message.to_owned(),
+                ack: None,
+            };
+            self.backplane.send(node, deliver);
+        } else if let Some(addr) = self.sessions.get(&id) {
+            addr.do_send(Message(message.to_owned()));
+        }
+    }
+}
+
+impl ChatServer {
+    /// Node a session or room lives on, `None` when it is this one
+    fn remote(&self, id: usize) -> Option<NodeId> {
+        Some(node_of(id)).filter(|node| *node != self.node)
+    }
+
+    /// Whether messages to the session get through, sessions of other nodes are taken to
+    fn connected(&self, id: usize) -> bool {
+        self.remote(id).is_some() || self.sessions.contains_key(&id)
+    }
+
+    /// Forward a command to the node of its room without waiting for an answer
+    fn tell(&self, node: NodeId, request: Request) {
+        let request = Envelope::Request {
+            from: self.node,
+            correlation: 0,
+            request,
+        };
+        self.backplane.send(node, request);
+    }
+
+    /// Forward a command to the node of its room. `answer` picks the result out of the
+    /// reply, `otherwise` is used when the node does not answer in time.
+    fn ask<T: 'static>(
+        &mut self,
+        node: NodeId,
+        request: Request,
+        answer: fn(Reply) -> Option<T>,
+        otherwise: T,
+    ) -> ResponseFuture<T> {
+        self.last_correlation += 1;
+        let correlation = self.last_correlation;
+        let (reply, replied) = oneshot::channel();
+        self.waiting.insert(correlation, reply);
+
+        let request = Envelope::Request {
+            from: self.node,
+            correlation,
+            request,
+        };
+        self.backplane.send(node, request);
+
+        Box::pin(async move {
+            match actix::clock::timeout(REMOTE_TIMEOUT, replied).await {
+                Ok(Ok(reply)) => answer(reply).unwrap_or(otherwise),
+                _ => otherwise,
+            }
+        })
+    }
+
+    /// Send a line to the members of a room, wherever it lives
+    fn announce(&self, room: usize, line: String, skip: usize) {
+        match self.remote(room) {
+            Some(node) => self.tell(node, Request::Announce { room, line, skip }),
+            None => self.send_message(&room, &line, skip),
+        }
+    }
+
+    /// Note that session `id` is now in `room` of this node, the node of the session lists its rooms
+    fn joined(&mut self, id: usize, room: usize) {
+        match self.remote(id) {
+            Some(node) => {
+                let membership = Envelope::Membership {
+                    session: id,
+                    room,
+                    member: true,
+                };
+                self.backplane.send(node, membership);
+            }
+            None => {
+                self.memberships.entry(id).or_default().insert(room);
+                self.session_changed(id);
+            }
+        }
+    }
+
+    fn parted(&mut self, id: usize, room: usize) {
+        match self.remote(id) {
+            Some(node) => {
+                let membership = Envelope::Membership {
+                    session: id,
+                    room,
+                    member: false,
+                };
+                self.backplane.send(node, membership);
+            }
+            None => {
+                if let Some(rooms) = self.memberships.get_mut(&id) {
+                    rooms.remove(&room);
+                }
+                self.session_changed(id);
+            }
+        }
+    }
+
+    /// Answer a command forwarded by another node for one of our rooms
+    fn serve(&mut self, request: Request) -> Option<Reply> {
+        match request {
+            Request::Message(msg) => self.message(msg),
+            Request::Ephemeral(msg) => self.ephemeral(msg),
+            Request::Ack(msg) => self.ack(msg),
+            Request::Members(msg) => return Some(Reply::Members(self.room_members(msg))),
+            Request::Room(msg) => return Some(Reply::Room(self.room_key(msg))),
+            Request::Direct(msg) => return Some(Reply::Direct(self.direct(msg))),
+            Request::Invite(msg) => return Some(Reply::Invite(self.invite(msg))),
+            Request::SendRoomKey(msg) => return Some(Reply::SendRoomKey(self.send_room_key(msg))),
+            Request::Join(msg) => return Some(Reply::Join(self.join(msg))),
+            Request::Leave(msg) => return Some(Reply::Left(self.leave_room(msg.id, msg.room))),
+            Request::Since(msg) => return Some(Reply::Since(self.since(msg))),
+            Request::RotateKey(msg) => return Some(Reply::Rotate(self.rotate(msg))),
+            Request::SetRotation(msg) => return Some(Reply::Owner(self.set_rotation(msg))),
+            Request::SetHistoryLimits(msg) => {
+                return Some(Reply::Owner(self.set_history_limits(msg)))
+            }
+            Request::TransferOffer(msg) => return Some(Reply::Transfer(self.transfer_offer(msg))),
+            Request::TransferUpdate(msg) => {
+                return Some(Reply::Transfer(self.transfer_update(msg)))
+            }
+            Request::Identities(msg) => return Some(Reply::Identities(self.room_identities(msg))),
+            Request::UploadShared(msg) => self.upload_shared(msg),
+            Request::Drop(id) => self.drop_session(id),
+            Request::Announce { room, line, skip } => self.send_message(&room, &line, skip),
+        }
+
+        None
+    }
+}
+
+impl ChatServer {
+    /// Next sequence number and a fresh message id for an event in the room
+    fn stamp(&mut self, room: usize) -> Option<Stamp> {
+        let id = self.rng.gen();
+        let state = self.rooms.get_mut(&room)?;
+        state.seq += 1;
+        let seq = state.seq;
+        self.changed(room);
+
+        Some(Stamp::new(seq, id))
+    }
+}
+
+impl ChatServer {
+    /// Stamp an event and keep it in the room history, returns the line to send
+    fn record(
+        &mut self,
+        room: usize,
+        event: &'static str,
+        sender: usize,
+        recipient: Option<usize>,
+        text: &str,
+    ) -> Option<String> {
+        let stamp = self.stamp(room)?;
+        let entry = Entry::new(stamp, event, sender, recipient, text);
+        let line = entry.line(room);
+
+        if let Some(state) = self.rooms.get_mut(&room) {
+            state.history.push(entry);
+        }
+
+        Some(line)
+    }
+}
+
+impl ChatServer {
+    fn is_member(&self, id: usize, room: usize) -> bool {
+        self.rooms
+            .get(&room)
+            .is_some_and(|state| state.members.contains(&id))
+    }
+}
+
+impl ChatServer {
+    fn members(&self, room: usize) -> Vec<usize> {
+        self.rooms
+            .get(&room)
+            .map(|state| state.members.iter().copied().collect())
+            .unwrap_or_default()
+    }
+}
+
+impl ChatServer {
+    /// Create a room with a fresh key that only `id` is a member of, returns the key
+    fn open_room(&mut self, room: usize, id: usize) -> usize {
+        let key = self.rng.gen();
+        let now = Instant::now();
+
+        self.rooms.insert(
+            room,
+            RoomState {
+                members: HashSet::from([id]),
+                owner: id,
+                key,
+                rotation: RotationPolicy::Manual,
+                last_rotation: now,
+                invites: HashSet::new(),
+                history: History::new(self.limits.history_len, self.limits.history_age),
+                seq: 0,
+                ephemeral: HashMap::new(),
+                ephemeral_dirty: false,
+                transfers: HashMap::new(),
+                identities: HashMap::new(),
+                created: now,
+                last_activity: now,
+                warned: false,
+            },
+        );
+        self.joined(id, room);
+        self.changed(room);
+
+        key
+    }
+}
+
+impl ChatServer {
+    /// Take the session out of one room, the room is destroyed once it is empty
+    fn leave_room(&mut self, id: usize, room: usize) -> bool {
+        let left = self
+            .rooms
+            .get_mut(&room)
+            .is_some_and(|state| state.members.remove(&id));
+        if !left {
+            return false;
+        }
+        self.parted(id, room);
+        self.changed(room);
+
+        let Some(state) = self.rooms.get_mut(&room) else {
+            return false;
+        };
+        state.identities.remove(&id);
+        if state.ephemeral.remove(&id).is_some() {
+            state.ephemeral_dirty = true;
+        }
+
+        // transfers can't go on without their sender, the others lose one recipient
+        let mut changed = Vec::new();
+        state.transfers.retain(|_, transfer| {
+            if !transfer.involves(id) {
+                return true;
+            }
+            if transfer.sender == id {
+                transfer.cancel();
+            } else {
+                transfer.drop_recipient(id);
+            }
+            changed.push(transfer.clone());
+            !transfer.state.is_final()
+        });
+
+        if state.members.is_empty() {
+            self.close_room(room);
+        } else {
+            let owner_left = state.owner == id;
+            if owner_left {
+                state.owner = state.members.iter().copied().min().unwrap_or_default();
+            }
+            let owner = state.owner;
+
+            // send message to other users
+            self.send_message(&room, &format!("/members {} {:?}", room, self.members(room)), 0);
+            if owner_left {
+                self.send_message(&room, &format!("/owner {} {}", room, owner), 0);
+            }
+            for transfer in changed {
+                self.announce_transfer(room, &transfer);
+            }
+        }
+
+        true
+    }
+}
+
+impl ChatServer {
+    /// Send the current state of a transfer to every member of the room
+    /// and how many recipients got it so far to its sender
+    fn announce_transfer(&mut self, room: usize, transfer: &Transfer) {
+        if let Some(stamp) = self.stamp(room) {
+            self.send_message(&room, &format!("/transfer {} {} {}", room, stamp, transfer.to_json()), 0);
+        }
+
+        let (received, total) = transfer.received();
+        self.send_to_session(
+            transfer.sender,
+            &format!("/transfer_status {} {} {}/{}", room, transfer.id, received, total),
+        );
+    }
+}
+
+impl ChatServer {
+    /// Destroy the room and hand its slot back to the allocator
+    fn close_room(&mut self, room: usize) {
+        if let Some(state) = self.rooms.remove(&room) {
+            for id in state.members {
+                self.parted(id, room);
+            }
+            self.queue.refund(room);
+            self.changed(room);
+        }
+    }
+}
+
+impl ChatServer {
+    /// Note message or signaling activity in the room, it keeps the room from idling out
+    fn touch(&mut self, room: usize) {
+        if let Some(state) = self.rooms.get_mut(&room) {
+            state.last_activity = Instant::now();
+            state.warned = false;
+            self.changed(room);
+        }
+    }
+}
+
+impl ChatServer {
+    /// Give the room a new key and send it to the current members only.
+    /// Invites asked for under the old key are dropped, the asking sessions are told so.
+    fn rotate_key(&mut self, room: usize) -> bool {
+        let key = self.rng.gen();
+        let Some(state) = self.rooms.get_mut(&room) else {
+            return false;
+        };
+
+        state.key = key;
+        state.last_rotation = Instant::now();
+        let invites = std::mem::take(&mut state.invites);
+
+        // keys are stamped but never kept in history
+        if let Some(stamp) = self.stamp(room) {
+            self.send_message(&room, &format!("/key {} {} {}", room, stamp, key), 0);
+        }
+        for id in invites {
+            self.send_to_session(id, &format!("/invite_expired {}", room));
+        }
+
+        true
+    }
+}
+
+impl ChatServer {
+    /// Rotate keys of rooms whose rotation timer ran out
+    fn rotate_keys(&mut self) {
+        let now = Instant::now();
+        let due: Vec<usize> = self
+            .rooms
+            .iter()
+            .filter(|(_, state)| match state.rotation {
+                RotationPolicy::Every(period) => state.last_rotation + period <= now,
+                _ => false,
+            })
+            .map(|(room, _)| *room)
+            .collect();
+
+        for room in due {
+            self.rotate_key(room);
+        }
+    }
+}
+
+impl ChatServer {
+    /// Drop ephemeral state that was not refreshed and send every changed room one coalesced update
+    fn flush_ephemeral(&mut self) {
+        let now = Instant::now();
+        let mut updates = Vec::new();
+
+        for (room, state) in &mut self.rooms {
+            let before = state.ephemeral.len();
+            state.ephemeral.retain(|_, (_, expires)| *expires > now);
+            if state.ephemeral.len() != before {
+                state.ephemeral_dirty = true;
+            }
+
+            if state.ephemeral_dirty {
+                state.ephemeral_dirty = false;
+
+                let mut line = format!("/state {}", room);
+                for (id, (activity, _)) in &state.ephemeral {
+                    line.push_str(&format!(" {}={}", id, activity.name()));
+                }
+                updates.push((*room, line));
+            }
+        }
+
+        for (room, line) in updates {
+            self.send_message(&room, &line, 0);
+        }
+    }
+}
+
+impl ChatServer {
+    /// Send a direct message line to a session, queueing it while the session is reconnecting
+    fn deliver(&mut self, id: usize, line: String, message_id: Option<u64>) {
+        if let Some(node) = self.remote(id) {
+            let deliver = Envelope::Deliver {
+                session: id,
+                line,
+                ack: message_id.map(|message_id| (message_id, self.node)),
+            };
+            self.backplane.send(node, deliver);
+            // the other node may have to queue it for a reconnecting recipient
+            if let Some(pending) =
+                message_id.and_then(|message_id| self.pending_acks.get_mut(&message_id))
+            {
+                pending.deadline = Some(Instant::now() + ACK_TIMEOUT + self.reconnect_grace);
+            }
+        } else if let Some(addr) = self.sessions.get(&id) {
+            addr.do_send(Message(line));
+            if let Some(pending) = message_id.and_then(|message_id| self.pending_acks.get_mut(&message_id)) {
+                pending.deadline = Some(Instant::now() + ACK_TIMEOUT);
+            }
+        } else if self.detached.contains_key(&id) {
+            self.outbox.entry(id).or_default().push((line, message_id));
+        }
+    }
+}
+
+impl ChatServer {
+    /// Tell the sender its acknowledged direct message did not make it
+    fn fail_ack(&mut self, message_id: u64) {
+        if let Some(pending) = self.pending_acks.remove(&message_id) {
+            self.send_to_session(pending.sender, &format!("/failed {} {}", pending.room, message_id));
+        }
+    }
+}
+
+impl ChatServer {
+    /// Remove a session for good, it leaves every room it joined
+    fn drop_session(&mut self, id: usize) {
+        self.sessions.remove(&id);
+        self.going_away.remove(&id);
+        self.tokens.remove(&id);
+        self.session_changed(id);
+        self.identities.remove(&id);
+        self.detached.remove(&id);
+
+        for (_, message_id) in self.outbox.remove(&id).unwrap_or_default() {
+            if let Some(message_id) = message_id {
+                self.fail_ack(message_id);
+            }
+        }
+
+        for room in self.memberships.remove(&id).unwrap_or_default() {
+            self.leave(id, room);
+        }
+    }
+}
+
+impl ChatServer {
+    /// Take a session of this node out of a room, wherever the room lives
+    fn leave(&mut self, id: usize, room: usize) {
+        match self.remote(room) {
+            Some(node) => self.tell(node, Request::Leave(Leave { id, room })),
+            None => {
+                self.leave_room(id, room);
+            }
+        }
+    }
+}
+
+impl ChatServer {
+    /// Drop sessions whose reconnect grace ran out and fail unacknowledged direct messages
+    fn expire_sessions(&mut self) {
+        let now = Instant::now();
+
+        let gone: Vec<usize> = self
+            .detached
+            .iter()
+            .filter(|(_, until)| **until <= now)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in gone {
+            self.drop_session(id);
+        }
+
+        let timed_out: Vec<u64> = self
+            .pending_acks
+            .iter()
+            .filter(|(_, pending)| pending.deadline.is_some_and(|deadline| deadline <= now))
+            .map(|(message_id, _)| *message_id)
+            .collect();
+        for message_id in timed_out {
+            self.fail_ack(message_id);
+        }
+
+        self.remote_acks.retain(|_, (_, until)| *until > now);
+        // the answer of a node that went away never comes
+        self.waiting.retain(|_, reply| !reply.is_closed());
+    }
+}
+
+impl ChatServer {
+    /// Warn members of rooms that are about to expire and close expired ones
+    fn expire_rooms(&mut self) {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        let mut warn = Vec::new();
+
+        for (room, state) in &mut self.rooms {
+            let Some(expires_at) = state.expires_at(&self.limits) else {
+                continue;
+            };
+
+            if expires_at <= now {
+                expired.push(*room);
+            } else if !state.warned && expires_at <= now + self.limits.warning {
+                state.warned = true;
+                warn.push((*room, expires_at - now));
+            }
+        }
+
+        for (room, left) in warn {
+            self.send_message(&room, &format!("/expiring {} {}", room, left.as_secs()), 0);
+        }
+
+        for room in expired {
+            log::info!("room {} expired", room);
+            self.send_message(&room, &format!("/expired {}", room), 0);
+            self.close_room(room);
+        }
+    }
+}
+
+impl Actor for ChatServer {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        if let Err(err) = self.backplane.subscribe(ctx.address().recipient()) {
+            log::error!("cant subscribe to the backplane: {}", err);
+        }
+        ctx.run_interval(EPHEMERAL_INTERVAL, |act, _| act.flush_ephemeral());
+        if self.store.is_some() {
+            ctx.run_interval(SAVE_INTERVAL, |act, _| act.save());
+        }
+        ctx.run_interval(HOUSEKEEPING_INTERVAL, |act, _| {
+            act.expire_sessions();
+            act.expire_rooms();
+            act.rotate_keys();
+            for state in act.rooms.values_mut() {
+                state.history.prune();
+            }
+        });
+    }
+}
+
+impl Handler<Connect> for ChatServer {
+    type Result = MessageResult<Connect>;
+
+    fn handle(&mut self, msg: Connect, _: &mut Context<Self>) -> Self::Result {
+        // register session with random id
+        let id = on_node(self.rng.gen(), self.node);
+        let token = self.rng.gen::<usize>();
+        self.sessions.insert(id, msg.addr);
+        self.tokens.insert(id, token);
+        if let Some(identity) = msg.identity {
+            self.identities.insert(id, identity);
+        }
+        self.session_changed(id);
+        // the room was reserved before the shutdown began
+        match self.draining {
+            Some(drain) => msg.going_away.do_send(GoingAway { drain }),
+            None => {
+                self.going_away.insert(id, msg.going_away);
+            }
+        }
+
+        // auto join session to main room
+        self.open_room(msg.room, id);
+
+        // send id back
+        MessageResult(Connected { id, token })
+    }
+}
+
+/// Handler for Disconnect message.
+impl Handler<Disconnect> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Disconnect, _: &mut Context<Self>) {
+        // remove address
+        if self.sessions.remove(&msg.id).is_none() {
+            return;
+        }
+        self.going_away.remove(&msg.id);
+
+        if self.reconnect_grace.is_zero() {
+            self.drop_session(msg.id);
+            return;
+        }
+
+        // keep the rooms of the session for a while, it may come back with /resume
+        self.detached
+            .insert(msg.id, Instant::now() + self.reconnect_grace);
+        for room in self.memberships.get(&msg.id).cloned().unwrap_or_default() {
+            self.announce(room, format!("/away {} {}", room, msg.id), msg.id);
+        }
+    }
+}
+
+impl Handler<Resume> for ChatServer {
+    type Result = MessageResult<Resume>;
+
+    fn handle(&mut self, msg: Resume, _: &mut Context<Self>) -> Self::Result {
+        if !self.detached.contains_key(&msg.resume_id)
+            || self.tokens.get(&msg.resume_id) != Some(&msg.token)
+            || self.identities.get(&msg.resume_id) != msg.identity.as_ref()
+        {
+            return MessageResult(ResumeResult::BadToken);
+        }
+
+        // the session made for the new socket is not needed anymore
+        match self.remote(msg.id) {
+            Some(node) => self.tell(node, Request::Drop(msg.id)),
+            None => self.drop_session(msg.id),
+        }
+
+        self.detached.remove(&msg.resume_id);
+        self.sessions.insert(msg.resume_id, msg.addr);
+        self.going_away.insert(msg.resume_id, msg.going_away);
+
+        for room in self.memberships.get(&msg.resume_id).cloned().unwrap_or_default() {
+            let back = format!("/back {} {}", room, msg.resume_id);
+            self.announce(room, back, msg.resume_id);
+        }
+        for (line, message_id) in self.outbox.remove(&msg.resume_id).unwrap_or_default() {
+            self.deliver(msg.resume_id, line, message_id);
+        }
+
+        MessageResult(ResumeResult::Resumed)
+    }
+}
+
+impl Handler<Ack> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Ack, _: &mut Context<Self>) {
+        match self.remote_acks.remove(&msg.message_id) {
+            Some((node, _)) => self.tell(node, Request::Ack(msg)),
+            None => self.ack(msg),
+        }
+    }
+}
+
+impl ChatServer {
+    fn ack(&mut self, msg: Ack) {
+        // only the recipient can confirm a message
+        if self
+            .pending_acks
+            .get(&msg.message_id)
+            .is_none_or(|pending| pending.recipient != msg.id)
+        {
+            return;
+        }
+
+        if let Some(pending) = self.pending_acks.remove(&msg.message_id) {
+            self.send_to_session(
+                pending.sender,
+                &format!("/delivered {} {}", pending.room, msg.message_id),
+            );
+        }
+    }
+}
+
+impl Handler<ClientMessage> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: ClientMessage, _: &mut Context<Self>) {
+        match self.remote(msg.room) {
+            Some(node) => self.tell(node, Request::Message(msg)),
+            None => self.message(msg),
+        }
+    }
+}
+
+impl ChatServer {
+    fn message(&mut self, msg: ClientMessage) {
+        if !self.is_member(msg.id, msg.room) {
+            self.send_to_session(msg.id, &format!("!!! not a member of room {}", msg.room));
+            return;
+        }
+
+        self.touch(msg.room);
+        if let Some(line) = self.record(msg.room, "/message", msg.id, None, &msg.msg) {
+            self.send_message(&msg.room, &line, msg.id);
+        }
+    }
+}
+
+impl Handler<Ephemeral> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Ephemeral, _: &mut Context<Self>) {
+        match self.remote(msg.room) {
+            Some(node) => self.tell(node, Request::Ephemeral(msg)),
+            None => self.ephemeral(msg),
+        }
+    }
+}
+
+impl ChatServer {
+    fn ephemeral(&mut self, msg: Ephemeral) {
+        let Some(state) = self
+            .rooms
+            .get_mut(&msg.room)
+            .filter(|state| state.members.contains(&msg.id))
+        else {
+            self.send_to_session(msg.id, &format!("!!! not a member of room {}", msg.room));
+            return;
+        };
+
+        match msg.activity {
+            Some(activity) => {
+                let expires = Instant::now() + EPHEMERAL_TTL;
+                // a refresh of the same activity only extends it, members are not told again
+                if let Some(previous) = state.ephemeral.insert(msg.id, (activity, expires)) {
+                    if previous.0 == activity {
+                        return;
+                    }
+                }
+                state.ephemeral_dirty = true;
+            }
+            None => {
+                if state.ephemeral.remove(&msg.id).is_some() {
+                    state.ephemeral_dirty = true;
+                }
+            }
+        }
+    }
+}
+
+impl Handler<ReserveRoom> for ChatServer {
+    type Result = MessageResult<ReserveRoom>;
+
+    fn handle(&mut self, msg: ReserveRoom, _: &mut Context<Self>) -> Self::Result {
+        if self.draining.is_some() {
+            return MessageResult(ReserveResult::ShuttingDown);
+        }
+
+        // sessions waiting to be resumed do not count, their client is reconnecting
+        let at_limit = |identity: &String| {
+            self.identity_limit.is_some_and(|limit| {
+                self.identities
+                    .iter()
+                    .filter(|(id, other)| *other == identity && !self.detached.contains_key(*id))
+                    .count()
+                    >= limit
+            })
+        };
+        if msg.identity.as_ref().is_some_and(at_limit) {
+            return MessageResult(ReserveResult::TooManySessions);
+        }
+
+        match self.queue.reserve() {
+            Some(room) => MessageResult(ReserveResult::Reserved(room)),
+            None => MessageResult(ReserveResult::FullQueue),
+        }
+    }
+}
+
+impl Handler<Shutdown> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Shutdown, _: &mut Context<Self>) {
+        self.draining = Some(msg.drain);
+        log::info!("sending {} sessions away", self.going_away.len());
+
+        // the sessions detach as their sockets close, so they can resume after a restart
+        for addr in self.going_away.values() {
+            addr.do_send(GoingAway { drain: msg.drain });
+        }
+    }
+}
+
+impl Handler<Save> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, _: Save, _: &mut Context<Self>) {
+        self.save();
+    }
+}
+
+impl Handler<TakeSnapshot> for ChatServer {
+    type Result = MessageResult<TakeSnapshot>;
+
+    fn handle(&mut self, _: TakeSnapshot, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.snapshot())
+    }
+}
+
+impl Handler<RefundRoom> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: RefundRoom, _: &mut Context<Self>) {
+        // the room may already be in use by a session that connected in between
+        if !self.rooms.contains_key(&msg.room) {
+            self.queue.refund(msg.room);
+        }
+    }
+}
+
+impl Handler<ListRooms> for ChatServer {
+    type Result = MessageResult<ListRooms>;
+
+    fn handle(&mut self, msg: ListRooms, _: &mut Context<Self>) -> Self::Result {
+        let rooms = self
+            .memberships
+            .get(&msg.id)
+            .map(|rooms| rooms.iter().copied().collect())
+            .unwrap_or_default();
+
+        MessageResult(rooms)
+    }
+}
+
+impl Handler<Members> for ChatServer {
+    type Result = ResponseFuture<Option<Vec<usize>>>;
+
+    fn handle(&mut self, mem: Members, _: &mut Context<Self>) -> Self::Result {
+        match self.remote(mem.room) {
+            Some(node) => self.ask(
+                node,
+                Request::Members(mem),
+                |reply| match reply {
+                    Reply::Members(members) => Some(members),
+                    _ => None,
+                },
+                None,
+            ),
+            None => Box::pin(ready(self.room_members(mem))),
+        }
+    }
+}
+
+impl ChatServer {
+    fn room_members(&self, mem: Members) -> Option<Vec<usize>> {
+        if !self.is_member(mem.id, mem.room) {
+            return None;
+        }
+
+        Some(self.members(mem.room))
+    }
+}
+
+impl Handler<Identities> for ChatServer {
+    type Result = ResponseFuture<Option<BTreeMap<usize, String>>>;
+
+    fn handle(&mut self, msg: Identities, _: &mut Context<Self>) -> Self::Result {
+        match self.remote(msg.room) {
+            Some(node) => self.ask(
+                node,
+                Request::Identities(msg),
+                |reply| match reply {
+                    Reply::Identities(identities) => Some(identities),
+                    _ => None,
+                },
+                None,
+            ),
+            None => Box::pin(ready(self.room_identities(msg))),
+        }
+    }
+}
+
+impl ChatServer {
+    fn room_identities(&self, msg: Identities) -> Option<BTreeMap<usize, String>> {
+        if !self.is_member(msg.id, msg.room) {
+            return None;
+        }
+        let state = self.rooms.get(&msg.room)?;
+
+        Some(
+            state
+                .members
+                .iter()
+                .filter_map(|id| {
+                    let identity = self.identities.get(id).or_else(|| state.identities.get(id))?;
+                    Some((*id, identity.clone()))
+                })
+                .collect(),
+        )
+    }
+}
+
+impl Handler<Direct> for ChatServer {
+    type Result = ResponseFuture<DirectResult>;
+
+    fn handle(&mut self, mess: Direct, _: &mut Context<Self>) -> Self::Result {
+        match self.remote(mess.room) {
+            Some(node) => self.ask(
+                node,
+                Request::Direct(mess),
+                |reply| match reply {
+                    Reply::Direct(result) => Some(result),
+                    _ => None,
+                },
+                DirectResult::IdDontExist,
+            ),
+            None => Box::pin(ready(self.direct(mess))),
+        }
+    }
+}
+
+impl ChatServer {
+    fn direct(&mut self, mess: Direct) -> DirectResult {
+        if !self.is_member(mess.id_from, mess.room) {
+            return DirectResult::NotMember;
+        }
+
+        if !self.is_member(mess.id_to, mess.room) {
+            return DirectResult::IdDontExist;
+        }
+
+        self.touch(mess.room);
+        let Some(stamp) = self.stamp(mess.room) else {
+            return DirectResult::IdDontExist;
+        };
+        let entry = Entry::new(stamp, "/direct_message", mess.id_from, Some(mess.id_to), &mess.mess);
+        let line = entry.line(mess.room);
+        if let Some(state) = self.rooms.get_mut(&mess.room) {
+            state.history.push(entry);
+        }
+
+        if !mess.ack {
+            self.deliver(mess.id_to, line, None);
+            return DirectResult::Send;
+        }
+
+        self.pending_acks.insert(
+            stamp.id,
+            PendingAck {
+                room: mess.room,
+                sender: mess.id_from,
+                recipient: mess.id_to,
+                deadline: None,
+            },
+        );
+        self.deliver(mess.id_to, line, Some(stamp.id));
+
+        DirectResult::Pending(stamp.id)
+    }
+}
+
+impl Handler<Room> for ChatServer {
+    type Result = ResponseFuture<Option<usize>>;
+
+    fn handle(&mut self, room: Room, _: &mut Self::Context) -> Self::Result {
+        match self.remote(room.name) {
+            Some(node) => self.ask(
+                node,
+                Request::Room(room),
+                |reply| match reply {
+                    Reply::Room(key) => Some(key),
+                    _ => None,
+                },
+                None,
+            ),
+            None => Box::pin(ready(self.room_key(room))),
+        }
+    }
+}
+
+impl ChatServer {
+    fn room_key(&self, room: Room) -> Option<usize> {
+        if !self.is_member(room.id, room.name) {
+            return None;
+        }
+
+        self.rooms.get(&room.name).map(|state| state.key)
+    }
+}
+
+impl Handler<Invite> for ChatServer {
+    type Result = ResponseFuture<InviteResult>;
+
+    fn handle(&mut self, data: Invite, _: &mut Self::Context) -> Self::Result {
+        // the key is sent back through a room the asking session is in,
+        // only the node of the session knows all of its rooms
+        if self
+            .memberships
+            .get(&data.id)
+            .is_none_or(|rooms| !rooms.contains(&data.from_room))
+        {
+            return Box::pin(ready(InviteResult::NotMember));
+        }
+
+        match self.remote(data.room) {
+            Some(node) => self.ask(
+                node,
+                Request::Invite(data),
+                |reply| match reply {
+                    Reply::Invite(result) => Some(result),
+                    _ => None,
+                },
+                InviteResult::RoomDontExist,
+            ),
+            None => Box::pin(ready(self.invite(data))),
+        }
+    }
+}
+
+impl ChatServer {
+    fn invite(&mut self, data: Invite) -> InviteResult {
+        if !self.rooms.contains_key(&data.room) {
+            return InviteResult::RoomDontExist;
+        }
+        if let Some(state) = self.rooms.get_mut(&data.room) {
+            state.invites.insert(data.id);
+        }
+        self.touch(data.room);
+
+        if let Some(line) = self.record(
+            data.room,
+            "/invite",
+            data.id,
+            None,
+            &data.from_room.to_string(),
+        ) {
+            self.send_message(&data.room, &line, 0);
+        }
+
+        InviteResult::Asked
+    }
+}
+
+impl Handler<SendRoomKey> for ChatServer {
+    type Result = ResponseFuture<SendRoomKeyResult>;
+
+    fn handle(&mut self, data: SendRoomKey, _: &mut Self::Context) -> Self::Result {
+        match self.remote(data.room) {
+            Some(node) => self.ask(
+                node,
+                Request::SendRoomKey(data),
+                |reply| match reply {
+                    Reply::SendRoomKey(result) => Some(result),
+                    _ => None,
+                },
+                SendRoomKeyResult::RoomDontExist,
+            ),
+            None => Box::pin(ready(self.send_room_key(data))),
+        }
+    }
+}
+
+impl ChatServer {
+    fn send_room_key(&mut self, data: SendRoomKey) -> SendRoomKeyResult {
+        let connected = self.connected(data.id);
+        let Some(state) = self.rooms.get_mut(&data.room) else {
+            return SendRoomKeyResult::RoomDontExist;
+        };
+        if !state.members.contains(&data.sender) {
+            return SendRoomKeyResult::NotMember;
+        }
+        // an invite is good for one key share
+        if !state.invites.remove(&data.id) || !connected {
+            return SendRoomKeyResult::NoInvite;
+        }
+
+        let key = state.key;
+        let Some(stamp) = self.stamp(data.room) else {
+            return SendRoomKeyResult::RoomDontExist;
+        };
+        // key shares are stamped but never kept in history
+        let share = RoomKeyShare {
+            room: data.room,
+            stamp,
+            from: data.sender,
+            key,
+        };
+        self.touch(data.room);
+
+        log::info!(
+            "session {} shared the key of room {} with session {}",
+            data.sender,
+            data.room,
+            data.id
+        );
+        self.send_to_session(data.id, &share.to_string());
+
+        let audit = format!("/key_shared {} {} {}", data.room, data.sender, data.id);
+        self.send_to_session(data.sender, &audit);
+        self.send_to_session(data.id, &audit);
+
+        SendRoomKeyResult::Send
+    }
+}
+
+impl Handler<Join> for ChatServer {
+    type Result = ResponseFuture<JoinResult>;
+
+    fn handle(&mut self, msg: Join, _: &mut Context<Self>) -> Self::Result {
+        match self.remote(msg.name) {
+            Some(node) => self.ask(
+                node,
+                Request::Join(msg),
+                |reply| match reply {
+                    Reply::Join(result) => Some(result),
+                    _ => None,
+                },
+                JoinResult::RoomDontExist,
+            ),
+            None => Box::pin(ready(self.join(msg))),
+        }
+    }
+}
+
+impl ChatServer {
+    fn join(&mut self, msg: Join) -> JoinResult {
         let Join {
             id,
             name,
             key,
-            room,
+            identity,
         } = msg;
 
-        if let Some(room_key) = self.keys.get(&name) {
-            if room_key != &key {
-                return MessageResult(JoinResult::BadKey);
-            };
-        } else {
-            return MessageResult(JoinResult::RoomDontExist);
+        // stale id of a room whose slot was already reused
+        if !self.queue.is_live(name) {
+            return JoinResult::RoomDontExist;
         }
 
-        if let Some(name ) = self.rooms.get(&name) {
-            if name.len() > 10 {
-                return MessageResult(JoinResult::FullRoom);   
-            }
-        } else {
-            return MessageResult(JoinResult::RoomDontExist);
+        let Some(state) = self.rooms.get_mut(&name) else {
+            return JoinResult::RoomDontExist;
+        };
+
+        if state.key != key {
+            return JoinResult::BadKey;
+        }
+        if state.members.contains(&id) {
+            return JoinResult::AlreadyMember;
+        }
+        if state.members.len() >= self.limits.capacity {
+            return JoinResult::FullRoom;
         }
 
-        // remove session from room
-        if let Some(sessions) = self.rooms.get_mut(&room) {
-            sessions.remove(&id);
+        state.members.insert(id);
+        state.invites.remove(&id);
+        if let Some(identity) = identity.filter(|_| node_of(id) != self.node) {
+            state.identities.insert(id, identity);
+        }
+        let rotate = state.rotation == RotationPolicy::OnJoin;
+        self.touch(name);
+        self.joined(id, name);
 
-            if sessions.is_empty() {
-                {
-                    let mut guard = self.queue.lock().unwrap();
-                    guard.refund(&room);
-                }
+        self.send_message(&name, &format!("/members {} {:?}", name, self.members(name)), id);
+
+        if rotate {
+            self.rotate_key(name);
+        }
 
-                self.rooms.remove(&room);
-                self.keys.remove(&room);
+        // catch the new member up on what was said before it joined
+        if let Some(state) = self.rooms.get(&name) {
+            for entry in state.history.chat() {
+                self.send_to_session(
+                    id,
+                    &format!("/history {} {} {} {}", name, entry.stamp, entry.sender, entry.text),
+                );
             }
+
+            let transfers: Vec<&Transfer> = state.transfers.values().collect();
+            self.send_to_session(
+                id,
+                &format!(
+                    "/transfers {} {}",
+                    name,
+                    serde_json::to_string(&transfers).unwrap_or_default()
+                ),
+            );
         }
 
-        self.rooms
-            .entry(name.clone())
-            .or_insert_with(HashSet::new)
-            .insert(id);
+        JoinResult::Joined(name)
+    }
+}
+
+impl Handler<Leave> for ChatServer {
+    type Result = ResponseActFuture<Self, LeaveResult>;
+
+    fn handle(&mut self, msg: Leave, _: &mut Context<Self>) -> Self::Result {
+        let id = msg.id;
+        let Some(node) = self.remote(msg.room) else {
+            let left = self.leave_room(msg.id, msg.room);
+            return Box::pin(fut::ready(self.moved(id, left)));
+        };
+
+        let left = self.ask(
+            node,
+            Request::Leave(msg),
+            |reply| match reply {
+                Reply::Left(left) => Some(left),
+                _ => None,
+            },
+            false,
+        );
+        Box::pin(
+            left.into_actor(self)
+                .map(move |left, act, _| act.moved(id, left)),
+        )
+    }
+}
 
-        let mut ids_vec = Vec::new();
+impl ChatServer {
+    /// Give a session that left a room a new private room of this node
+    fn moved(&mut self, id: usize, left: bool) -> LeaveResult {
+        if !left {
+            return LeaveResult::NotMember;
+        }
 
-        if let Some(ids) = self.rooms.get(&msg.room) {
-            for room_id in ids {
-                ids_vec.push(room_id.to_owned());
+        // leave first, so the refunded room can be handed out again
+        match self.queue.reserve() {
+            Some(room) => {
+                let key = self.open_room(room, id);
+                LeaveResult::Left { room, key }
             }
+            None => LeaveResult::FullQueue,
+        }
+    }
+}
+
+impl Handler<RotateKey> for ChatServer {
+    type Result = ResponseFuture<RotateResult>;
+
+    fn handle(&mut self, msg: RotateKey, _: &mut Context<Self>) -> Self::Result {
+        match self.remote(msg.room) {
+            Some(node) => self.ask(
+                node,
+                Request::RotateKey(msg),
+                |reply| match reply {
+                    Reply::Rotate(result) => Some(result),
+                    _ => None,
+                },
+                RotateResult::RoomDontExist,
+            ),
+            None => Box::pin(ready(self.rotate(msg))),
+        }
+    }
+}
+
+impl ChatServer {
+    fn rotate(&mut self, msg: RotateKey) -> RotateResult {
+        match self.rooms.get(&msg.room) {
+            None => return RotateResult::RoomDontExist,
+            Some(state) if state.owner != msg.id => return RotateResult::NotOwner,
+            _ => (),
+        }
+
+        if self.rotate_key(msg.room) {
+            RotateResult::Rotated
+        } else {
+            RotateResult::RoomDontExist
+        }
+    }
+}
+
+impl Handler<SetRotation> for ChatServer {
+    type Result = ResponseFuture<OwnerResult>;
+
+    fn handle(&mut self, msg: SetRotation, _: &mut Context<Self>) -> Self::Result {
+        match self.remote(msg.room) {
+            Some(node) => self.ask(
+                node,
+                Request::SetRotation(msg),
+                owner_reply,
+                OwnerResult::RoomDontExist,
+            ),
+            None => Box::pin(ready(self.set_rotation(msg))),
+        }
+    }
+}
+
+impl ChatServer {
+    fn set_rotation(&mut self, msg: SetRotation) -> OwnerResult {
+        let Some(state) = self.rooms.get_mut(&msg.room) else {
+            return OwnerResult::RoomDontExist;
+        };
+        if state.owner != msg.id {
+            return OwnerResult::NotOwner;
+        }
+
+        state.rotation = msg.policy;
+        state.last_rotation = Instant::now();
+        self.changed(msg.room);
+
+        OwnerResult::Set
+    }
+}
+
+impl Handler<SetHistoryLimits> for ChatServer {
+    type Result = ResponseFuture<OwnerResult>;
+
+    fn handle(&mut self, msg: SetHistoryLimits, _: &mut Context<Self>) -> Self::Result {
+        match self.remote(msg.room) {
+            Some(node) => self.ask(
+                node,
+                Request::SetHistoryLimits(msg),
+                owner_reply,
+                OwnerResult::RoomDontExist,
+            ),
+            None => Box::pin(ready(self.set_history_limits(msg))),
+        }
+    }
+}
+
+impl ChatServer {
+    fn set_history_limits(&mut self, msg: SetHistoryLimits) -> OwnerResult {
+        let Some(state) = self.rooms.get_mut(&msg.room) else {
+            return OwnerResult::RoomDontExist;
+        };
+        if state.owner != msg.id {
+            return OwnerResult::NotOwner;
+        }
+
+        state.history.max_len = msg.max_len.min(self.limits.history_len);
+        state.history.max_age = msg.max_age.min(self.limits.history_age);
+        state.history.prune();
+        self.changed(msg.room);
+
+        OwnerResult::Set
+    }
+}
+
+fn owner_reply(reply: Reply) -> Option<OwnerResult> {
+    match reply {
+        Reply::Owner(result) => Some(result),
+        _ => None,
+    }
+}
+
+impl Handler<Since> for ChatServer {
+    type Result = ResponseFuture<Option<(Vec<String>, u64)>>;
+
+    fn handle(&mut self, msg: Since, _: &mut Context<Self>) -> Self::Result {
+        match self.remote(msg.room) {
+            Some(node) => self.ask(
+                node,
+                Request::Since(msg),
+                |reply| match reply {
+                    Reply::Since(events) => Some(events),
+                    _ => None,
+                },
+                None,
+            ),
+            None => Box::pin(ready(self.since(msg))),
+        }
+    }
+}
+
+impl ChatServer {
+    fn since(&self, msg: Since) -> Option<(Vec<String>, u64)> {
+        if !self.is_member(msg.id, msg.room) {
+            return None;
+        }
+
+        self.rooms.get(&msg.room).map(|state| {
+            let lines = state
+                .history
+                .since(msg.seq, msg.id)
+                .map(|entry| entry.line(msg.room))
+                .collect();
+
+            (lines, state.seq)
+        })
+    }
+}
+
+impl Handler<TransferOffer> for ChatServer {
+    type Result = ResponseFuture<TransferResult>;
+
+    fn handle(&mut self, msg: TransferOffer, _: &mut Context<Self>) -> Self::Result {
+        match self.remote(msg.room) {
+            Some(node) => self.ask(
+                node,
+                Request::TransferOffer(msg),
+                transfer_reply,
+                TransferResult::NotMember,
+            ),
+            None => Box::pin(ready(self.transfer_offer(msg))),
+        }
+    }
+}
+
+impl ChatServer {
+    fn transfer_offer(&mut self, msg: TransferOffer) -> TransferResult {
+        if !self.is_member(msg.id, msg.room) {
+            return TransferResult::NotMember;
+        }
+        let recipients: HashSet<usize> = match msg.offer.to {
+            Some(to) if to != msg.id && self.is_member(to, msg.room) => HashSet::from([to]),
+            Some(_) => return TransferResult::IdDontExist,
+            // offered to the whole room, each member accepts or declines on its own
+            None => self
+                .members(msg.room)
+                .into_iter()
+                .filter(|member| *member != msg.id)
+                .collect(),
+        };
+        if recipients.is_empty() {
+            return TransferResult::IdDontExist;
+        }
+
+        let transfer = Transfer::new(self.rng.gen(), msg.id, recipients, msg.offer);
+        let transfer_id = transfer.id;
+        self.touch(msg.room);
+        self.announce_transfer(msg.room, &transfer);
+
+        if let Some(state) = self.rooms.get_mut(&msg.room) {
+            state.transfers.insert(transfer_id, transfer);
+        }
+
+        TransferResult::Offered(transfer_id)
+    }
+}
+
+impl Handler<TransferUpdate> for ChatServer {
+    type Result = ResponseFuture<TransferResult>;
+
+    fn handle(&mut self, msg: TransferUpdate, _: &mut Context<Self>) -> Self::Result {
+        match self.remote(msg.room) {
+            Some(node) => self.ask(
+                node,
+                Request::TransferUpdate(msg),
+                transfer_reply,
+                TransferResult::NotMember,
+            ),
+            None => Box::pin(ready(self.transfer_update(msg))),
         }
-        // send message to other users
-        self.send_message(&room, &format!("/members {:?}", ids_vec), 0);
+    }
+}
+
+impl ChatServer {
+    fn transfer_update(&mut self, msg: TransferUpdate) -> TransferResult {
+        let Some(state) = self
+            .rooms
+            .get_mut(&msg.room)
+            .filter(|state| state.members.contains(&msg.id))
+        else {
+            return TransferResult::NotMember;
+        };
+
+        // the room owner moderates transfers
+        let moderator = state.owner == msg.id;
+        let Some(transfer) = state.transfers.get_mut(&msg.transfer) else {
+            return TransferResult::NotFound;
+        };
+        if !msg.update.apply(transfer, msg.id, moderator) {
+            return TransferResult::NotAllowed;
+        }
+
+        let transfer = transfer.clone();
+        if transfer.state.is_final() {
+            state.transfers.remove(&transfer.id);
+        }
+
+        self.touch(msg.room);
+        self.announce_transfer(msg.room, &transfer);
+
+        TransferResult::Updated
+    }
+}
+
+fn transfer_reply(reply: Reply) -> Option<TransferResult> {
+    match reply {
+        Reply::Transfer(result) => Some(result),
+        _ => None,
+    }
+}
+
+impl Handler<Authorize> for ChatServer {
+    type Result = bool;
+
+    fn handle(&mut self, msg: Authorize, _: &mut Context<Self>) -> bool {
+        // the room may live on another shard, the session knows its rooms
+        self.tokens.get(&msg.id) == Some(&msg.token)
+            && self
+                .memberships
+                .get(&msg.id)
+                .is_some_and(|rooms| rooms.contains(&msg.room))
+    }
+}
+
+impl Handler<UploadShared> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: UploadShared, _: &mut Context<Self>) {
+        match self.remote(msg.room) {
+            Some(node) => self.tell(node, Request::UploadShared(msg)),
+            None => self.upload_shared(msg),
+        }
+    }
+}
 
-        let mut ids_vec = Vec::new();
+impl ChatServer {
+    fn upload_shared(&mut self, msg: UploadShared) {
+        self.touch(msg.room);
+        // kept in history so members that come back later still learn about the file
+        if let Some(line) = self.record(msg.room, "/upload", msg.id, None, &msg.upload) {
+            self.send_message(&msg.room, &line, 0);
+        }
+    }
+}
 
-        if let Some(ids) = self.rooms.get(&name) {
-            for room_id in ids {
-                ids_vec.push(room_id.to_owned());
+impl Handler<Remote> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Remote, _: &mut Context<Self>) {
+        match msg.0 {
+            Envelope::Deliver { session, line, ack } => {
+                if let Some((message_id, node)) = ack {
+                    let until = Instant::now() + ACK_TIMEOUT + self.reconnect_grace;
+                    self.remote_acks.insert(message_id, (node, until));
+                }
+                self.deliver(session, line, None);
+            }
+            Envelope::Membership {
+                session,
+                room,
+                member,
+            } => {
+                // the session may be gone already, its rooms were left then
+                if !self.tokens.contains_key(&session) {
+                    return;
+                }
+                let rooms = self.memberships.entry(session).or_default();
+                match member {
+                    true => rooms.insert(room),
+                    false => rooms.remove(&room),
+                };
+                self.session_changed(session);
+            }
+            Envelope::Request {
+                from,
+                correlation,
+                request,
+            } => {
+                let reply = self.serve(request);
+                if let Some(reply) = reply.filter(|_| correlation != 0) {
+                    let reply = Envelope::Reply { correlation, reply };
+                    self.backplane.send(from, reply);
+                }
+            }
+            Envelope::Reply { correlation, reply } => {
+                if let Some(waiting) = self.waiting.remove(&correlation) {
+                    let _ = waiting.send(reply);
+                }
             }
         }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::backplane::Hub;
+
+    /// Stands in for the websocket of a session and keeps the lines sent to it
+    #[derive(Default)]
+    struct Client(Arc<Mutex<Vec<String>>>);
+
+    impl Actor for Client {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<Message> for Client {
+        type Result = ();
+
+        fn handle(&mut self, msg: Message, _: &mut Context<Self>) {
+            self.0.lock().unwrap().push(msg.0);
+        }
+    }
+
+    impl Handler<GoingAway> for Client {
+        type Result = ();
+
+        fn handle(&mut self, _: GoingAway, _: &mut Context<Self>) {}
+    }
+
+    /// Lines the client got so far, sent after them so it comes back once they arrived
+    struct Take;
+
+    impl actix::Message for Take {
+        type Result = Vec<String>;
+    }
+
+    impl Handler<Take> for Client {
+        type Result = MessageResult<Take>;
+
+        fn handle(&mut self, _: Take, _: &mut Context<Self>) -> Self::Result {
+            MessageResult(std::mem::take(&mut *self.0.lock().unwrap()))
+        }
+    }
+
+    /// Run what a timer of the server runs without waiting for it
+    struct Tick(fn(&mut ChatServer));
+
+    impl actix::Message for Tick {
+        type Result = ();
+    }
+
+    impl Handler<Tick> for ChatServer {
+        type Result = ();
+
+        fn handle(&mut self, msg: Tick, _: &mut Context<Self>) {
+            (msg.0)(self);
+        }
+    }
+
+    struct Session {
+        id: usize,
+        token: usize,
+        room: usize,
+        client: Addr<Client>,
+    }
+
+    impl Session {
+        async fn lines(&self) -> Vec<String> {
+            self.client.send(Take).await.unwrap()
+        }
+    }
+
+    fn limits() -> RoomLimits {
+        RoomLimits {
+            capacity: 8,
+            max_age: None,
+            idle_timeout: None,
+            warning: Duration::from_secs(60),
+            history_len: 50,
+            history_age: Duration::from_secs(600),
+        }
+    }
+
+    fn start(rooms: usize, limits: RoomLimits, reconnect_grace: Duration) -> Addr<ChatServer> {
+        let backplane = Box::new(Hub::default().node(0));
+        ChatServer::new(RoomAllocator::new(rooms, 0), limits, reconnect_grace, None, backplane).start()
+    }
+
+    /// Register a session in a room of its own, like a new websocket does
+    async fn connect(server: &Addr<ChatServer>) -> Session {
+        let ReserveResult::Reserved(room) = server.send(ReserveRoom { identity: None }).await.unwrap() else {
+            panic!("no free room");
+        };
+        let client = Client::default().start();
+        let Connected { id, token } = server
+            .send(Connect {
+                addr: client.clone().recipient(),
+                going_away: client.clone().recipient(),
+                room,
+                identity: None,
+            })
+            .await
+            .unwrap();
+
+        Session {
+            id,
+            token,
+            room,
+            client,
+        }
+    }
+
+    async fn join(server: &Addr<ChatServer>, session: &Session, owner: &Session) -> JoinResult {
+        let key = server
+            .send(Room {
+                id: owner.id,
+                name: owner.room,
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        server
+            .send(Join {
+                id: session.id,
+                name: owner.room,
+                key,
+                identity: None,
+            })
+            .await
+            .unwrap()
+    }
+
+    async fn leave(server: &Addr<ChatServer>, session: &Session, room: usize) -> LeaveResult {
+        server
+            .send(Leave {
+                id: session.id,
+                room,
+            })
+            .await
+            .unwrap()
+    }
+
+    async fn rooms(server: &Addr<ChatServer>, session: &Session) -> Vec<usize> {
+        server.send(ListRooms { id: session.id }).await.unwrap()
+    }
+
+    /// Lines that start with `prefix`, members and stamps vary from run to run
+    fn starting(lines: &[String], prefix: &str) -> usize {
+        lines.iter().filter(|line| line.starts_with(prefix)).count()
+    }
+
+    #[actix_web::test]
+    async fn owner_leaving_hands_the_room_on() {
+        let server = start(4, limits(), Duration::ZERO);
+        let (a, b) = (connect(&server).await, connect(&server).await);
+        assert!(matches!(join(&server, &b, &a).await, JoinResult::Joined(room) if room == a.room));
+        assert_eq!(starting(&a.lines().await, &format!("/members {} ", a.room)), 1);
+        assert_eq!(b.lines().await, [format!("/transfers {} []", a.room)]);
+
+        let LeaveResult::Left { room, .. } = leave(&server, &a, a.room).await else {
+            panic!("owner did not get a room of its own");
+        };
+        assert_ne!(room, a.room);
+        assert_eq!(rooms(&server, &a).await, [room]);
+        assert_eq!(
+            b.lines().await,
+            [
+                format!("/members {} [{}]", a.room, b.id),
+                format!("/owner {} {}", a.room, b.id),
+            ]
+        );
+
+        // the new owner may rotate the key, the old one is not even a member anymore
+        let rotate = |id| RotateKey { id, room: a.room };
+        assert!(matches!(server.send(rotate(a.id)).await.unwrap(), RotateResult::NotOwner));
+        assert!(matches!(server.send(rotate(b.id)).await.unwrap(), RotateResult::Rotated));
+        assert!(matches!(leave(&server, &a, a.room).await, LeaveResult::NotMember));
+    }
+
+    #[actix_web::test]
+    async fn last_member_leaving_gets_the_freed_room_back() {
+        let server = start(1, limits(), Duration::ZERO);
+        let a = connect(&server).await;
+
+        let LeaveResult::Left { room, key } = leave(&server, &a, a.room).await else {
+            panic!("the only room was not handed out again");
+        };
+        // same slot, but the old id is stale
+        assert_ne!(room, a.room);
+        assert_eq!(rooms(&server, &a).await, [room]);
+        let stale = Join {
+            id: a.id,
+            name: a.room,
+            key,
+            identity: None,
+        };
+        assert!(matches!(server.send(stale).await.unwrap(), JoinResult::RoomDontExist));
+    }
+
+    #[actix_web::test]
+    async fn leaving_with_a_full_queue_can_leave_no_room() {
+        let server = start(2, limits(), Duration::ZERO);
+        let (a, b) = (connect(&server).await, connect(&server).await);
+        join(&server, &b, &a).await;
+        join(&server, &a, &b).await;
+
+        // both rooms stay open for the other member, there is none left to move to
+        assert!(matches!(leave(&server, &b, a.room).await, LeaveResult::FullQueue));
+        assert!(matches!(leave(&server, &b, b.room).await, LeaveResult::FullQueue));
+        assert!(rooms(&server, &b).await.is_empty());
+        assert!(matches!(leave(&server, &b, b.room).await, LeaveResult::NotMember));
+
+        let members = server.send(Members { id: a.id, room: b.room }).await.unwrap();
+        assert_eq!(members, Some(vec![a.id]));
+    }
+
+    #[actix_web::test]
+    async fn rooms_expire_with_their_members_told() {
+        let limits = RoomLimits {
+            max_age: Some(Duration::ZERO),
+            ..limits()
+        };
+        let server = start(2, limits, Duration::ZERO);
+        let (a, b) = (connect(&server).await, connect(&server).await);
+        join(&server, &b, &a).await;
+        a.lines().await;
+        b.lines().await;
+
+        server.send(Tick(ChatServer::expire_rooms)).await.unwrap();
+        assert_eq!(a.lines().await, [format!("/expired {}", a.room)]);
+        let mut told = b.lines().await;
+        told.sort();
+        let mut expired = vec![format!("/expired {}", a.room), format!("/expired {}", b.room)];
+        expired.sort();
+        assert_eq!(told, expired);
+
+        assert!(rooms(&server, &a).await.is_empty());
+        assert!(rooms(&server, &b).await.is_empty());
+        let key = server.send(Room { id: a.id, name: a.room }).await.unwrap();
+        assert_eq!(key, None);
+        // both slots are free again
+        for _ in 0..2 {
+            let reserved = server.send(ReserveRoom { identity: None }).await.unwrap();
+            assert!(matches!(reserved, ReserveResult::Reserved(_)));
+        }
+    }
+
+    #[actix_web::test]
+    async fn members_are_warned_once_until_the_room_is_used_again() {
+        let limits = RoomLimits {
+            idle_timeout: Some(Duration::from_secs(3600)),
+            warning: Duration::from_secs(7200),
+            ..limits()
+        };
+        let server = start(2, limits, Duration::ZERO);
+        let (a, b) = (connect(&server).await, connect(&server).await);
+        join(&server, &b, &a).await;
+        a.lines().await;
+        b.lines().await;
+
+        server.send(Tick(ChatServer::expire_rooms)).await.unwrap();
+        let expiring = format!("/expiring {} ", a.room);
+        assert_eq!(starting(&a.lines().await, &expiring), 1);
+        assert_eq!(starting(&b.lines().await, &expiring), 1);
+
+        server.send(Tick(ChatServer::expire_rooms)).await.unwrap();
+        assert!(a.lines().await.is_empty());
+
+        let message = ClientMessage {
+            id: b.id,
+            msg: "still here".to_owned(),
+            room: a.room,
+        };
+        server.send(message).await.unwrap();
+        server.send(Tick(ChatServer::expire_rooms)).await.unwrap();
+        let lines = a.lines().await;
+        assert_eq!(starting(&lines, &format!("/message {} ", a.room)), 1);
+        assert_eq!(starting(&lines, &expiring), 1);
+        assert!(rooms(&server, &a).await.contains(&a.room));
+    }
+
+    async fn key(server: &Addr<ChatServer>, member: &Session, room: usize) -> usize {
+        server.send(Room { id: member.id, name: room }).await.unwrap().unwrap()
+    }
+
+    /// Keys in the `/key` lines of a room
+    fn keys(lines: &[String], room: usize) -> Vec<usize> {
+        let prefix = format!("/key {} ", room);
+        lines
+            .iter()
+            .filter_map(|line| line.strip_prefix(&prefix)?.rsplit(' ').next()?.parse().ok())
+            .collect()
+    }
+
+    #[actix_web::test]
+    async fn rotated_key_goes_to_members_and_drops_invites() {
+        let server = start(4, limits(), Duration::ZERO);
+        let (a, b, c) = (connect(&server).await, connect(&server).await, connect(&server).await);
+        join(&server, &b, &a).await;
+        let old = key(&server, &a, a.room).await;
+        let invite = Invite {
+            id: c.id,
+            room: a.room,
+            from_room: c.room,
+        };
+        assert!(matches!(server.send(invite).await.unwrap(), InviteResult::Asked));
+        a.lines().await;
+        b.lines().await;
+
+        let rotate = |id| RotateKey { id, room: a.room };
+        assert!(matches!(server.send(rotate(b.id)).await.unwrap(), RotateResult::NotOwner));
+        assert!(matches!(server.send(rotate(a.id)).await.unwrap(), RotateResult::Rotated));
+        let new = key(&server, &a, a.room).await;
+        assert_ne!(new, old);
+        assert_eq!(keys(&a.lines().await, a.room), [new]);
+        assert_eq!(keys(&b.lines().await, a.room), [new]);
+        assert_eq!(c.lines().await, [format!("/invite_expired {}", a.room)]);
+
+        let share = SendRoomKey {
+            sender: a.id,
+            room: a.room,
+            id: c.id,
+        };
+        assert!(matches!(server.send(share).await.unwrap(), SendRoomKeyResult::NoInvite));
+        let stale = Join {
+            id: c.id,
+            name: a.room,
+            key: old,
+            identity: None,
+        };
+        assert!(matches!(server.send(stale).await.unwrap(), JoinResult::BadKey));
+    }
+
+    #[actix_web::test]
+    async fn rotation_policy_rotates_on_join_and_on_time() {
+        let server = start(4, limits(), Duration::ZERO);
+        let (a, b) = (connect(&server).await, connect(&server).await);
+        let policy = |id, policy| SetRotation {
+            id,
+            room: a.room,
+            policy,
+        };
+        let set = server.send(policy(b.id, RotationPolicy::OnJoin)).await.unwrap();
+        assert!(matches!(set, OwnerResult::NotOwner));
+        let set = server.send(policy(a.id, RotationPolicy::OnJoin)).await.unwrap();
+        assert!(matches!(set, OwnerResult::Set));
+
+        let old = key(&server, &a, a.room).await;
+        join(&server, &b, &a).await;
+        let new = key(&server, &a, a.room).await;
+        assert_ne!(new, old);
+        assert_eq!(keys(&a.lines().await, a.room), [new]);
+        assert_eq!(keys(&b.lines().await, a.room), [new]);
+
+        let every = RotationPolicy::Every(Duration::from_millis(1));
+        server.send(policy(a.id, every)).await.unwrap();
+        actix::clock::sleep(Duration::from_millis(5)).await;
+        server.send(Tick(ChatServer::rotate_keys)).await.unwrap();
+        assert_eq!(keys(&b.lines().await, a.room), [key(&server, &a, a.room).await]);
+
+        server.send(policy(a.id, RotationPolicy::Manual)).await.unwrap();
+        actix::clock::sleep(Duration::from_millis(5)).await;
+        server.send(Tick(ChatServer::rotate_keys)).await.unwrap();
+        assert!(b.lines().await.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn room_key_is_shared_once_per_invite() {
+        let server = start(4, limits(), Duration::ZERO);
+        let (a, b, c) = (connect(&server).await, connect(&server).await, connect(&server).await);
+        join(&server, &b, &a).await;
+        let share = |sender| SendRoomKey {
+            sender,
+            room: a.room,
+            id: c.id,
+        };
+        let invite = |from_room| Invite {
+            id: c.id,
+            room: a.room,
+            from_room,
+        };
+
+        assert!(matches!(server.send(share(a.id)).await.unwrap(), SendRoomKeyResult::NoInvite));
+        // the key goes back through a room the asking session is in
+        assert!(matches!(server.send(invite(b.room)).await.unwrap(), InviteResult::NotMember));
+        assert!(matches!(server.send(invite(c.room)).await.unwrap(), InviteResult::Asked));
+        assert_eq!(starting(&b.lines().await, &format!("/invite {} ", a.room)), 1);
+        a.lines().await;
+
+        assert!(matches!(server.send(share(c.id)).await.unwrap(), SendRoomKeyResult::NotMember));
+        assert!(matches!(server.send(share(a.id)).await.unwrap(), SendRoomKeyResult::Send));
+        let key = key(&server, &a, a.room).await;
+        let audit = format!("/key_shared {} {} {}", a.room, a.id, c.id);
+        let lines = c.lines().await;
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with(&format!("/send {} ", a.room)));
+        assert!(lines[0].ends_with(&format!(" {} {}", a.id, key)));
+        assert_eq!(lines[1], audit);
+        assert_eq!(a.lines().await, [audit]);
+        assert!(b.lines().await.is_empty());
+
+        assert!(matches!(server.send(share(b.id)).await.unwrap(), SendRoomKeyResult::NoInvite));
+        let enter = Join {
+            id: c.id,
+            name: a.room,
+            key,
+            identity: None,
+        };
+        assert!(matches!(server.send(enter).await.unwrap(), JoinResult::Joined(_)));
+    }
+
+    #[actix_web::test]
+    async fn ephemeral_state_is_coalesced_and_runs_out() {
+        let server = start(4, limits(), Duration::ZERO);
+        let (a, b, c) = (connect(&server).await, connect(&server).await, connect(&server).await);
+        join(&server, &b, &a).await;
+        a.lines().await;
+        b.lines().await;
+        let activity = |id, activity| Ephemeral {
+            id,
+            room: a.room,
+            activity,
+        };
+        let flush = || server.send(Tick(ChatServer::flush_ephemeral));
+
+        server.send(activity(a.id, Some(Activity::Typing))).await.unwrap();
+        server.send(activity(a.id, Some(Activity::Uploading))).await.unwrap();
+        flush().await.unwrap();
+        let uploading = format!("/state {} {}=uploading", a.room, a.id);
+        assert_eq!(a.lines().await, [uploading.as_str()]);
+        assert_eq!(b.lines().await, [uploading]);
+
+        // refreshing the same activity only extends it
+        server.send(activity(a.id, Some(Activity::Uploading))).await.unwrap();
+        flush().await.unwrap();
+        assert!(b.lines().await.is_empty());
+
+        server.send(activity(a.id, None)).await.unwrap();
+        flush().await.unwrap();
+        assert_eq!(b.lines().await, [format!("/state {}", a.room)]);
+
+        server.send(activity(b.id, Some(Activity::Typing))).await.unwrap();
+        flush().await.unwrap();
+        a.lines().await;
+        server
+            .send(Tick(|server| {
+                for state in server.rooms.values_mut() {
+                    for (_, expires) in state.ephemeral.values_mut() {
+                        *expires = Instant::now();
+                    }
+                }
+            }))
+            .await
+            .unwrap();
+        flush().await.unwrap();
+        assert_eq!(a.lines().await, [format!("/state {}", a.room)]);
+
+        server.send(activity(c.id, Some(Activity::Typing))).await.unwrap();
+        assert_eq!(c.lines().await, [format!("!!! not a member of room {}", a.room)]);
+        flush().await.unwrap();
+        assert!(a.lines().await.is_empty());
+    }
+
+    fn direct(from: &Session, to: &Session, room: usize, ack: bool) -> Direct {
+        Direct {
+            room,
+            id_to: to.id,
+            id_from: from.id,
+            mess: "hi".to_owned(),
+            ack,
+        }
+    }
+
+    #[actix_web::test]
+    async fn direct_messages_are_confirmed_by_their_recipient_only() {
+        let server = start(4, limits(), Duration::ZERO);
+        let (a, b, c) = (connect(&server).await, connect(&server).await, connect(&server).await);
+        join(&server, &b, &a).await;
+        a.lines().await;
+        b.lines().await;
+
+        let sent = server.send(direct(&c, &b, a.room, false)).await.unwrap();
+        assert!(matches!(sent, DirectResult::NotMember));
+        let sent = server.send(direct(&a, &c, a.room, false)).await.unwrap();
+        assert!(matches!(sent, DirectResult::IdDontExist));
+        let sent = server.send(direct(&a, &b, a.room, false)).await.unwrap();
+        assert!(matches!(sent, DirectResult::Send));
+        assert_eq!(starting(&b.lines().await, &format!("/direct_message {} ", a.room)), 1);
+
+        let DirectResult::Pending(message_id) = server.send(direct(&a, &b, a.room, true)).await.unwrap() else {
+            panic!("acknowledged message is not pending");
+        };
+        assert_eq!(b.lines().await.len(), 1);
+        let ack = |id| Ack { id, message_id };
+        server.send(ack(a.id)).await.unwrap();
+        assert!(a.lines().await.is_empty());
+        server.send(ack(b.id)).await.unwrap();
+        server.send(ack(b.id)).await.unwrap();
+        assert_eq!(a.lines().await, [format!("/delivered {} {}", a.room, message_id)]);
+
+        // unconfirmed messages fail once their deadline passes
+        let DirectResult::Pending(message_id) = server.send(direct(&a, &b, a.room, true)).await.unwrap() else {
+            panic!("acknowledged message is not pending");
+        };
+        server
+            .send(Tick(|server| {
+                for pending in server.pending_acks.values_mut() {
+                    pending.deadline = Some(Instant::now());
+                }
+                server.expire_sessions();
+            }))
+            .await
+            .unwrap();
+        assert_eq!(a.lines().await, [format!("/failed {} {}", a.room, message_id)]);
+    }
 
-        self.send_message(&name, &format!("/members {:?}", ids_vec), id);
+    #[actix_web::test]
+    async fn detached_session_gets_its_queue_on_resume() {
+        let server = start(4, limits(), Duration::from_secs(60));
+        let (a, b) = (connect(&server).await, connect(&server).await);
+        join(&server, &b, &a).await;
+        a.lines().await;
+        b.lines().await;
+
+        server.send(Disconnect { id: b.id }).await.unwrap();
+        assert_eq!(a.lines().await, [format!("/away {} {}", a.room, b.id)]);
+        let DirectResult::Pending(message_id) = server.send(direct(&a, &b, a.room, true)).await.unwrap() else {
+            panic!("acknowledged message is not pending");
+        };
+
+        let socket = connect(&server).await;
+        let resume = |token| Resume {
+            id: socket.id,
+            resume_id: b.id,
+            token,
+            addr: socket.client.clone().recipient(),
+            going_away: socket.client.clone().recipient(),
+            identity: None,
+        };
+        let resumed = server.send(resume(b.token.wrapping_add(1))).await.unwrap();
+        assert!(matches!(resumed, ResumeResult::BadToken));
+        let resumed = server.send(resume(b.token)).await.unwrap();
+        assert!(matches!(resumed, ResumeResult::Resumed));
+
+        // the queued message arrives on the new socket, the session made for it is gone
+        assert_eq!(starting(&socket.lines().await, &format!("/direct_message {} ", a.room)), 1);
+        assert!(b.lines().await.is_empty());
+        assert_eq!(a.lines().await, [format!("/back {} {}", a.room, b.id)]);
+        assert!(rooms(&server, &socket).await.is_empty());
+        server.send(Ack { id: b.id, message_id }).await.unwrap();
+        assert_eq!(a.lines().await, [format!("/delivered {} {}", a.room, message_id)]);
+    }
 
-        MessageResult(JoinResult::Joined(name))
+    #[actix_web::test]
+    async fn session_that_does_not_come_back_leaves_and_fails_its_queue() {
+        let server = start(4, limits(), Duration::from_millis(1));
+        let (a, b) = (connect(&server).await, connect(&server).await);
+        join(&server, &b, &a).await;
+        server.send(Disconnect { id: b.id }).await.unwrap();
+        let DirectResult::Pending(message_id) = server.send(direct(&a, &b, a.room, true)).await.unwrap() else {
+            panic!("acknowledged message is not pending");
+        };
+        a.lines().await;
+
+        actix::clock::sleep(Duration::from_millis(5)).await;
+        server.send(Tick(ChatServer::expire_sessions)).await.unwrap();
+        assert_eq!(
+            a.lines().await,
+            [
+                format!("/failed {} {}", a.room, message_id),
+                format!("/members {} [{}]", a.room, a.id),
+            ]
+        );
+        assert!(rooms(&server, &b).await.is_empty());
+        let resumed = server
+            .send(Resume {
+                id: a.id,
+                resume_id: b.id,
+                token: b.token,
+                addr: b.client.clone().recipient(),
+                going_away: b.client.clone().recipient(),
+                identity: None,
+            })
+            .await
+            .unwrap();
+        assert!(matches!(resumed, ResumeResult::BadToken));
     }
 }
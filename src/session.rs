@@ -1,47 +1,123 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::future::Future;
 use std::time::{Duration, Instant};
 
 use actix::prelude::*;
 use actix_web_actors::ws::{self};
 
+use crate::router::Router;
 use crate::server::{self};
+use crate::transfer::{Offer, Update};
 
-/// How often heartbeat pings are sent
-const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+/// Commands a session can have waiting for the chat server at once,
+/// the ones after them wait in the backlog
+const IN_FLIGHT_LIMIT: u64 = 32;
 
-/// How long before lack of client response causes a timeout
-const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Commands that can wait in the backlog, later ones are refused
+const BACKLOG_LIMIT: usize = 64;
 
 #[derive(Debug)]
 pub struct WsChatSession {
     /// unique session id
     pub id: usize,
 
-    /// Client must send ping at least once per 10 seconds (CLIENT_TIMEOUT),
+    /// secret the client can resume this session with after a reconnect
+    pub token: usize,
+
+    /// Client must send ping at least once per `client_timeout`,
     /// otherwise we drop connection.
     pub hb: Instant,
 
-    /// joined room
+    /// How often heartbeat pings are sent
+    pub hb_interval: Duration,
+
+    /// How long before lack of client response causes a timeout
+    pub client_timeout: Duration,
+
+    /// personal room reserved for this session when it connected,
+    /// the session can join further rooms on top of it
     pub room: usize,
 
-    /// Chat server
+    /// subject of the client certificate the connection was made with
+    pub identity: Option<String>,
+
+    /// Chat server shard the session lives on
     pub addr: Addr<server::ChatServer>,
+
+    /// Reaches the shards of rooms, for commands only the shard of the room can answer
+    pub router: Router,
+
+    /// Commands waiting for the chat server and their replies
+    pub requests: Requests,
+}
+
+/// Commands of a session that wait for the chat server. Each gets a correlation id
+/// in the order it came in, replies that come back early are held until the ones
+/// of earlier commands went out.
+#[derive(Debug, Default)]
+pub struct Requests {
+    /// correlation id of the next command
+    next: u64,
+    /// first command whose reply did not go out yet
+    sent: u64,
+    /// replies waiting for the ones of earlier commands
+    ready: BTreeMap<u64, Vec<String>>,
+    /// a command that changes the session, like `/join` or `/resume`, is waiting
+    /// for the chat server, the commands after it have to wait for its answer
+    blocked: bool,
+    /// commands that came in while blocked or with too many in flight
+    backlog: VecDeque<String>,
+    /// the session was stopped while blocked and stops once the command is answered
+    closing: bool,
+}
+
+impl Requests {
+    /// Correlation id for a new command
+    fn open(&mut self) -> u64 {
+        self.next += 1;
+        self.next - 1
+    }
+
+    /// Keep the reply to command `correlation`, returns the lines that can go out now
+    fn answer(&mut self, correlation: u64, lines: Vec<String>) -> Vec<String> {
+        self.ready.insert(correlation, lines);
+
+        let mut out = Vec::new();
+        while let Some(lines) = self.ready.remove(&self.sent) {
+            out.extend(lines);
+            self.sent += 1;
+        }
+        out
+    }
+
+    /// New commands have to wait in the backlog
+    fn busy(&self) -> bool {
+        self.blocked || self.next - self.sent >= IN_FLIGHT_LIMIT || !self.backlog.is_empty()
+    }
+}
+
+/// Lines answering one command
+#[derive(Debug, Default)]
+struct Reply(Vec<String>);
+
+impl Reply {
+    fn text(&mut self, line: impl Into<String>) {
+        self.0.push(line.into());
+    }
 }
 
 impl WsChatSession {
-    /// helper method that sends ping to client every 5 seconds (HEARTBEAT_INTERVAL).
+    /// helper method that sends ping to client every `hb_interval`.
     ///
     /// also this method checks heartbeats from client
     fn hb(&self, ctx: &mut ws::WebsocketContext<Self>) {
-        ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
+        ctx.run_interval(self.hb_interval, |act, ctx| {
             // check client heartbeats
-            if Instant::now().duration_since(act.hb) > CLIENT_TIMEOUT {
+            if Instant::now().duration_since(act.hb) > act.client_timeout {
                 // heartbeat timed out
                 //println!("Websocket Client heartbeat failed, disconnecting!");
 
-                // notify chat server
-                act.addr.do_send(server::Disconnect { id: act.id, room: act.room.clone() });
-
-                // stop actor
+                // stop actor, `stopped` notifies the chat server
                 ctx.stop();
 
                 // don't try to send a ping
@@ -51,6 +127,96 @@ impl WsChatSession {
             ctx.ping(b"");
         });
     }
+
+    /// Send a command to the chat server without holding up the session, heartbeats
+    /// and messages from the server keep coming in meanwhile. `answer` writes the
+    /// reply, it goes out after the replies to the commands before it.
+    fn request<R, F>(
+        &mut self,
+        ctx: &mut ws::WebsocketContext<Self>,
+        request: impl Future<Output = Result<R, MailboxError>> + 'static,
+        answer: F,
+    ) where
+        R: 'static,
+        F: FnOnce(Result<R, MailboxError>, &mut Self, &mut Reply) + 'static,
+    {
+        self.dispatch(ctx, request, answer, false);
+    }
+
+    /// `request` for commands that change the session, the commands after it wait
+    /// until it is answered so they see the session it leaves behind
+    fn ordered<R, F>(
+        &mut self,
+        ctx: &mut ws::WebsocketContext<Self>,
+        request: impl Future<Output = Result<R, MailboxError>> + 'static,
+        answer: F,
+    ) where
+        R: 'static,
+        F: FnOnce(Result<R, MailboxError>, &mut Self, &mut Reply) + 'static,
+    {
+        self.dispatch(ctx, request, answer, true);
+    }
+
+    fn dispatch<R, F>(
+        &mut self,
+        ctx: &mut ws::WebsocketContext<Self>,
+        request: impl Future<Output = Result<R, MailboxError>> + 'static,
+        answer: F,
+        ordered: bool,
+    ) where
+        R: 'static,
+        F: FnOnce(Result<R, MailboxError>, &mut Self, &mut Reply) + 'static,
+    {
+        let correlation = self.requests.open();
+        self.requests.blocked |= ordered;
+
+        request
+            .into_actor(self)
+            .then(move |res, act, ctx| {
+                let mut reply = Reply::default();
+                answer(res, act, &mut reply);
+                act.reply(correlation, reply, ctx);
+                if ordered {
+                    act.requests.blocked = false;
+                }
+                act.run_backlog(ctx);
+                fut::ready(())
+            })
+            .spawn(ctx);
+    }
+
+    /// Answer a command right away, still after the replies to earlier ones
+    fn text(&mut self, ctx: &mut ws::WebsocketContext<Self>, line: impl Into<String>) {
+        let correlation = self.requests.open();
+        let mut reply = Reply::default();
+        reply.text(line);
+        self.reply(correlation, reply, ctx);
+    }
+
+    fn reply(&mut self, correlation: u64, reply: Reply, ctx: &mut ws::WebsocketContext<Self>) {
+        for line in self.requests.answer(correlation, reply.0) {
+            ctx.text(line);
+        }
+    }
+
+    /// Run the commands that waited for a reply, or stop if the session was
+    /// stopped while a command that changes it was out
+    fn run_backlog(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        if self.requests.closing {
+            if !self.requests.blocked {
+                ctx.stop();
+            }
+            return;
+        }
+
+        while !self.requests.blocked && self.requests.next - self.requests.sent < IN_FLIGHT_LIMIT {
+            match self.requests.backlog.pop_front() {
+                Some(line) => self.command(&line, ctx),
+                None => break,
+            }
+        }
+    }
+
 }
 
 impl Actor for WsChatSession {
@@ -62,37 +228,38 @@ impl Actor for WsChatSession {
         // we'll start heartbeat process on session start.
         self.hb(ctx);
 
-        // register self in chat server. `AsyncContext::wait` register
-        // future within context, but context waits until this future resolves
-        // before processing any other events.
-        // HttpContext::state() is instance of WsChatSessionState, state is shared
-        // across all routes within application
+        // register self in chat server. Commands the client sends before
+        // the session has an id wait for it in the backlog.
         let addr = ctx.address();
-        self.addr
-            .send(server::Connect {
-                addr: addr.recipient(),
-                room: self.room.to_owned()
-            })
-            .into_actor(self)
-            .then(|res, act, ctx| {
-                match res {
-                    Ok(res) => act.id = res,
-                    // something is wrong with chat server
-                    _ => {
-                        act.addr.do_send(server::Disconnect { id: act.id, room: act.room.clone() });
-                        ctx.stop()
-                    },
-                }
-                fut::ready(())
-            })
-            .wait(ctx);
+        let request = self.addr.send(server::Connect {
+            addr: addr.clone().recipient(),
+            going_away: addr.recipient(),
+            room: self.room,
+            identity: self.identity.clone(),
+        });
+        self.ordered(ctx, request, |res, act, _| match res {
+            Ok(res) => {
+                act.id = res.id;
+                act.token = res.token;
+            }
+            // something is wrong with chat server
+            _ => act.requests.closing = true,
+        });
     }
 
+    /// Keeps running until a command that changes the session is answered,
+    /// so `stopped` disconnects the session it ended up with
     fn stopping(&mut self, _: &mut Self::Context) -> Running {
+        if self.requests.blocked {
+            self.requests.closing = true;
+            return Running::Continue;
+        }
         Running::Stop
     }
+    /// The only place that notifies the chat server, a second `Disconnect`
+    /// could detach the session again after it was resumed on a new socket
     fn stopped(&mut self, _: &mut Self::Context) {
-        self.addr.do_send(server::Disconnect { id: self.id, room: self.room.clone() });
+        self.addr.do_send(server::Disconnect { id: self.id });
     }
 }
 
@@ -105,12 +272,25 @@ impl Handler<server::Message> for WsChatSession {
     }
 }
 
+/// Tell the client when to come back and close with `1001 Going Away`
+impl Handler<server::GoingAway> for WsChatSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: server::GoingAway, ctx: &mut Self::Context) {
+        ctx.text(format!("/going_away {}", msg.drain.as_secs()));
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Away,
+            description: Some("server is shutting down".to_owned()),
+        }));
+        ctx.stop();
+    }
+}
+
 /// WebSocket message handler
 impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChatSession {
     fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
         let msg = match msg {
             Err(_) => {
-                self.addr.do_send(server::Disconnect { id: self.id, room: self.room.clone() });
                 ctx.stop();
                 return;
             }
@@ -126,247 +306,607 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChatSession {
                 self.hb = Instant::now();
             }
             ws::Message::Text(text) => {
-                //println!("WEBSOCKET MESSAGE: {text:?}");
-                let m = text.trim();
-                // we check for /sss type of messages
-                if m.starts_with('/') {
-                    let v: Vec<&str> = m.splitn(2, ' ').collect();
-                    match v[0] {
-                        "/list" => {
-                            // Send ListRooms message to chat server and wait for
-                            // response
-
-                            self.addr
-                                .send(server::ListRooms)
-                                .into_actor(self)
-                                .then(|res, _, ctx| {
+                if !self.requests.busy() {
+                    self.command(text.trim(), ctx);
+                } else if self.requests.backlog.len() < BACKLOG_LIMIT {
+                    self.requests.backlog.push_back(text.trim().to_string());
+                } else {
+                    self.text(ctx, "!!! too many commands waiting");
+                }
+            }
+            ws::Message::Binary(_) => println!("Unexpected binary"),
+            ws::Message::Close(reason) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+            ws::Message::Continuation(_) => {
+                ctx.stop();
+            }
+            ws::Message::Nop => (),
+        }
+    }
+}
+
+impl WsChatSession {
+    /// Run one text command of the client
+    fn command(&mut self, m: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        // we check for /sss type of messages
+        if m.starts_with('/') {
+            let v: Vec<&str> = m.splitn(2, ' ').collect();
+            match v[0] {
+                "/list" => {
+                    let request = self.addr.send(server::ListRooms { id: self.id });
+                    self.request(ctx, request, |res, act, reply| {
+                        match res {
+                            Ok(rooms) => {
+                                for room in rooms {
+                                    reply.text(room.to_string());
+                                }
+                            }
+                            Err(err) => {
+                                log::error!("cant list rooms of session {}: {}", act.id, err);
+                                reply.text("!!! somethig go wrong");
+                            }
+                        }
+                    });
+                }
+                "/ping" => {
+                    self.text(ctx, "/pong".to_string());
+                }
+                "/invite" => {
+                    if v.len() != 2 {
+                        self.text(ctx, "!!! syntax error");
+                        return;
+                    }
+                    let invite_data: Vec<&str> = v[1].splitn(2, ' ').collect();
+                    if let Some(room) = invite_data.first().and_then(|x| x.parse::<usize>().ok()) {
+                        if let Some(from_room) = invite_data.get(1).and_then(|x| x.parse::<usize>().ok()) {
+                            let request = self.addr.send(server::Invite {
+                                id: self.id,
+                                room,
+                                from_room,
+                            });
+                            self.request(ctx, request, move |res, _, reply| {
+                                match res {
+                                    Ok(ivite_res) => {
+                                        match ivite_res {
+                                            server::InviteResult::Asked => reply.text("/asked"),
+                                            server::InviteResult::RoomDontExist => reply.text("!!! room does not exist"),
+                                            server::InviteResult::NotMember => reply.text("!!! not a member of room"),
+                                        }
+                                    }
+                                    Err(err) => {
+                                        log::error!("cant ask into room {}: {}", room, err);
+                                        reply.text("!!! somethig go wrong");
+                                    }
+                                }
+                            });
+                        } else {
+                            self.text(ctx, "!!! room to answer in must be integer");
+                        }
+                    } else {
+                        self.text(ctx, "!!! room name required ");
+                    }
+                }
+                "/send" => {
+                    if v.len() == 2 {
+                        let user_data: Vec<&str> = v[1].splitn(2, ' ').collect();
+                        if let Some(room) = user_data.first().and_then(|x| x.parse::<usize>().ok()) {
+                            if let Some(id) = user_data.get(1).and_then(|x| x.parse::<usize>().ok()) {
+                                let request = self.router.shard(room).send(server::SendRoomKey{
+                                    sender: self.id,
+                                    room,
+                                    id,
+                                });
+                                self.request(ctx, request, |res, _, reply| {
                                     match res {
-                                        Ok(rooms) => {
-                                            for room in rooms {
-                                                ctx.text(room.to_string());
+                                        Ok(send_res) => {
+                                            match send_res {
+                                                server::SendRoomKeyResult::Send => reply.text("/send"),
+                                                server::SendRoomKeyResult::RoomDontExist => reply.text("!!! room does not exist"),
+                                                server::SendRoomKeyResult::NotMember => reply.text("!!! not a member of room"),
+                                                server::SendRoomKeyResult::NoInvite => reply.text("!!! user has no invite to room"),
                                             }
+
                                         }
-                                        _ => println!("!!! something is wrong"),
+                                        _ => reply.text("!!! somethig go wrong"),
                                     }
-                                    fut::ready(())
-                                })
-                                .wait(ctx)
-                            // .wait(ctx) pauses all events in context,
-                            // so actor wont receive any new messages until it get list
-                            // of rooms back
-                        }
-                        "/ping" => {
-                            ctx.text("/pong".to_string());
-                        }
-                        "/invite" => {
-                            if v.len() != 2 {
-                                ctx.text("!!! syntax error");
-                                return;
+                                });
+                            } else {
+                                self.text(ctx, "!!! user id must be integer");
                             }
-                            if let Some(room) = v[1].parse::<usize>().ok() {
-                                self.addr
-                                .send(server::Invite { 
+                        } else {
+                            self.text(ctx, "!!! room name must be integer");
+                        }
+                    }else{
+                        self.text(ctx, "!!! room name and user id are required");
+                    }
+                }
+                "/join" => {
+                    if v.len() == 2 {
+                        let room_data: Vec<&str> = v[1].splitn(2, ' ').collect();
+                        if let Some(name) = room_data.first().and_then(|x| x.parse::<usize>().ok()) {
+                            if let Some(key) = room_data.get(1).and_then(|x| x.parse::<usize>().ok()) {
+
+                                let request = self.router.shard(name).send(server::Join {
                                     id: self.id,
-                                    room: room.clone(),
-                                    from_room: self.room
-                                })
-                                .into_actor(self)
-                                .then(|res, _, ctx| {
+                                    name,
+                                    key,
+                                    identity: self.identity.clone(),
+                                });
+                                self.ordered(ctx, request, |res, _, reply| {
                                     match res {
-                                        Ok(ivite_res) => {
-                                            match ivite_res {
-                                                server::InviteResult::Asked => ctx.text("/asked"),
-                                                server::InviteResult::RoomDontExist => ctx.text("!!! room does not exist")
+                                        Ok(join_res) => {
+                                            match join_res {
+                                                server::JoinResult::Joined(room) => reply.text(format!("/joined {}", room)),
+                                                server::JoinResult::RoomDontExist => reply.text("!!! room does not exist"),
+                                                server::JoinResult::BadKey => reply.text("!!! bad key"),
+                                                server::JoinResult::FullRoom => reply.text("!!! full room"),
+                                                server::JoinResult::AlreadyMember => reply.text("!!! already a member of room"),
                                             }
+
                                         }
-                                        _ => println!("!!! something is wrong"),
+                                        _ => reply.text("!!! somethig go wrong"),
                                     }
-                                    fut::ready(())
-                                })
-                                .wait(ctx)
+                                });
                             } else {
-                                ctx.text("!!! room name required ");
+                                self.text(ctx, "!!! room key must be integer");
                             }
+                        } else {
+                            self.text(ctx, "!!! room name must be integer");
                         }
-                        "/send" => {
-                            if v.len() == 2 {
-                                let user_data: Vec<&str> = v[1].splitn(2, ' ').collect();
-                                if let Some(room) = user_data.get(0).map_or(None, |x| x.parse::<usize>().ok()) {
-                                    if let Some(id) = user_data.get(1).map_or(None, |x| x.parse::<usize>().ok()) {
-                                        self.addr.send(server::SendRoomKey{
-                                            room: room.clone(),
-                                            from_room: self.room,
-                                            id: id.clone()
-                                        })
-                                        .into_actor(self)
-                                        .then(|res, _, ctx| {
-                                            match res {
-                                                Ok(send_res) => {
-                                                    match send_res {
-                                                        server::SendRoomKeyResult::Send => ctx.text("/send"),
-                                                        server::SendRoomKeyResult::RoomDontExist => ctx.text("!!! room does not exist")
-                                                    }
-                                                    
-                                                }
-                                                _ => ctx.text("!!! somethig go wrong"),
-                                            }
-                                            fut::ready(())
-                                        })
-                                        .wait(ctx)
-                                    } else {
-                                        ctx.text("!!! user id must be integer");
-                                    }
-                                } else {
-                                    ctx.text("!!! room name must be integer");
+
+                    } else {
+                        self.text(ctx, "!!! room name and key is required");
+                    }
+                }
+                "/leave" => {
+                    if v.len() != 2 {
+                        self.text(ctx, "!!! syntax error");
+                        return;
+                    }
+                    if let Ok(room) = v[1].parse::<usize>() {
+                        let request = self.addr.send(server::Leave{ id: self.id, room });
+                        self.ordered(ctx, request, move |res, act, reply| {
+                            match res {
+                                Ok(server::LeaveResult::Left { room: new_room, key }) => {
+                                    act.room = new_room;
+                                    reply.text(format!("/left {} {} {}", room, new_room, key));
                                 }
-                            }else{
-                                ctx.text("!!! room name and key is required");
+                                Ok(server::LeaveResult::FullQueue) => {
+                                    reply.text(format!("/left {}", room));
+                                    reply.text("!!! full queue");
+                                }
+                                Ok(server::LeaveResult::NotMember) => reply.text("!!! not a member of room"),
+                                _ => reply.text("!!! somethig go wrong"),
                             }
-                        }
-                        "/join" => {
-                            if v.len() == 2 {
-                                let room_data: Vec<&str> = v[1].splitn(2, ' ').collect();
-                                if let Some(name) = room_data.get(0).map_or(None, |x| x.parse::<usize>().ok()) {
-                                    if let Some(key) = room_data.get(1).map_or(None, |x| x.parse::<usize>().ok()) {
-
-                                        self.addr.send(server::Join {
-                                            id: self.id,
-                                            name: name.clone(),
-                                            key,
-                                            room: self.room.clone(),
-                                        })
-                                        .into_actor(self)
-                                        .then(|res, actor, ctx| {
-                                            match res {
-                                                Ok(join_res) => {
-                                                    match join_res {
-                                                        server::JoinResult::Joined(room) => {
-                                                            actor.room = room;
-                                                            ctx.text("/joined");
-                                                        },
-                                                        server::JoinResult::RoomDontExist => ctx.text("!!! room does not exist"),
-                                                        server::JoinResult::BadKey => ctx.text("!!! bad key"),
-                                                        server::JoinResult::FullRoom => ctx.text("!!! full room")
-                                                    }
-                                                    
-                                                }
-                                                _ => ctx.text("!!! somethig go wrong"),
-                                            }
-                                            fut::ready(())
-                                        })
-                                        .wait(ctx)
-                                    } else {
-                                        ctx.text("!!! room key must be integer");
-                                    }
-                                } else {
-                                    ctx.text("!!! room name must be integer");
+                        });
+                    } else {
+                        self.text(ctx, "!!! room name must be integer");
+                    }
+                }
+                "/rotate" => {
+                    if v.len() != 2 {
+                        self.text(ctx, "!!! syntax error");
+                        return;
+                    }
+                    if let Ok(room) = v[1].parse::<usize>() {
+                        let request = self.router.shard(room).send(server::RotateKey{ id: self.id, room });
+                        self.request(ctx, request, move |res, _, reply| {
+                            match res {
+                                // the new key itself arrives as /key like for every other member
+                                Ok(server::RotateResult::Rotated) => reply.text(format!("/rotated {}", room)),
+                                Ok(server::RotateResult::NotOwner) => reply.text("!!! only the room owner can rotate the key"),
+                                Ok(server::RotateResult::RoomDontExist) => reply.text("!!! room does not exist"),
+                                _ => reply.text("!!! somethig go wrong"),
+                            }
+                        });
+                    } else {
+                        self.text(ctx, "!!! room name must be integer");
+                    }
+                }
+                "/rotate_policy" => {
+                    if v.len() != 2 {
+                        self.text(ctx, "!!! syntax error");
+                        return;
+                    }
+                    let data: Vec<&str> = v[1].splitn(2, ' ').collect();
+                    if let Some(room) = data.first().and_then(|x| x.parse::<usize>().ok()) {
+                        let policy = match data.get(1) {
+                            Some(&"manual") => server::RotationPolicy::Manual,
+                            Some(&"join") => server::RotationPolicy::OnJoin,
+                            Some(secs) => match secs.parse::<u64>() {
+                                Ok(secs) if secs > 0 => server::RotationPolicy::Every(Duration::from_secs(secs)),
+                                _ => {
+                                    self.text(ctx, "!!! policy must be manual, join or seconds");
+                                    return;
                                 }
-                                
+                            },
+                            None => {
+                                self.text(ctx, "!!! policy must be manual, join or seconds");
+                                return;
+                            }
+                        };
+                        let policy_name = data[1].to_string();
+                        let request = self.router.shard(room).send(server::SetRotation{ id: self.id, room, policy });
+                        self.request(ctx, request, move |res, _, reply| {
+                            match res {
+                                Ok(server::OwnerResult::Set) => reply.text(format!("/rotate_policy {} {}", room, policy_name)),
+                                Ok(server::OwnerResult::NotOwner) => reply.text("!!! only the room owner can change key rotation"),
+                                Ok(server::OwnerResult::RoomDontExist) => reply.text("!!! room does not exist"),
+                                _ => reply.text("!!! somethig go wrong"),
+                            }
+                        });
+                    } else {
+                        self.text(ctx, "!!! room name must be integer");
+                    }
+                }
+                "/history_limit" => {
+                    if v.len() != 2 {
+                        self.text(ctx, "!!! syntax error");
+                        return;
+                    }
+                    let data: Vec<&str> = v[1].splitn(3, ' ').collect();
+                    if let Some(room) = data.first().and_then(|x| x.parse::<usize>().ok()) {
+                        if let Some(max_len) = data.get(1).and_then(|x| x.parse::<usize>().ok()) {
+                            if let Some(max_age) = data.get(2).and_then(|x| x.parse::<u64>().ok()) {
+                                let request = self.router.shard(room).send(server::SetHistoryLimits{
+                                    id: self.id,
+                                    room,
+                                    max_len,
+                                    max_age: Duration::from_secs(max_age),
+                                });
+                                self.request(ctx, request, move |res, _, reply| {
+                                    match res {
+                                        Ok(server::OwnerResult::Set) => reply.text(format!("/history_limit {}", room)),
+                                        Ok(server::OwnerResult::NotOwner) => reply.text("!!! only the room owner can change history limits"),
+                                        Ok(server::OwnerResult::RoomDontExist) => reply.text("!!! room does not exist"),
+                                        _ => reply.text("!!! somethig go wrong"),
+                                    }
+                                });
                             } else {
-                                ctx.text("!!! room name and key is required");
+                                self.text(ctx, "!!! history age must be seconds");
                             }
+                        } else {
+                            self.text(ctx, "!!! history length must be integer");
                         }
-                        "/room" => {
-                            if v.len() != 1 {
-                                ctx.text("!!! syntax error");
-                                return;
-                            }
-                            self.addr.send(server::Room{ name: self.room.clone() })
-                            .into_actor(self)
-                            .then(|res, session, ctx| {
+                    } else {
+                        self.text(ctx, "!!! room name must be integer");
+                    }
+                }
+                "/since" => {
+                    if v.len() != 2 {
+                        self.text(ctx, "!!! syntax error");
+                        return;
+                    }
+                    let data: Vec<&str> = v[1].splitn(2, ' ').collect();
+                    if let Some(room) = data.first().and_then(|x| x.parse::<usize>().ok()) {
+                        if let Some(seq) = data.get(1).and_then(|x| x.parse::<u64>().ok()) {
+                            let request = self.router.shard(room).send(server::Since{ id: self.id, room, seq });
+                            self.request(ctx, request, move |res, _, reply| {
                                 match res {
-                                    Ok(key) => {
-                                        match key {
-                                            Some(x) => ctx.text(format!("/room {:?} {:?}", session.room, x)),
-                                            None => ctx.text("!!! cant get key"),
+                                    Ok(Some((lines, latest))) => {
+                                        for line in lines {
+                                            reply.text(line);
                                         }
+                                        reply.text(format!("/since {} {}", room, latest));
                                     }
-                                    _ => ctx.text("!!! somethig go wrong"),
+                                    Ok(None) => reply.text("!!! not a member of room"),
+                                    _ => reply.text("!!! somethig go wrong"),
                                 }
-                                fut::ready(())
-                            })
-                            .wait(ctx)
+                            });
+                        } else {
+                            self.text(ctx, "!!! sequence number must be integer");
                         }
-                        "/id" => {
-                            if v.len() != 1 {
-                                ctx.text("!!! syntax error");
-                                return;
-                            }
-                            ctx.text(format!("/id {:?}", self.id))
-                        }
-                        "/members" => {
-                            if v.len() != 1 {
-                                ctx.text("!!! syntax error");
-                                return;
+                    } else {
+                        self.text(ctx, "!!! room name must be integer");
+                    }
+                }
+                "/room" => {
+                    if v.len() != 2 {
+                        self.text(ctx, "!!! syntax error");
+                        return;
+                    }
+                    if let Ok(name) = v[1].parse::<usize>() {
+                        let request = self.router.shard(name).send(server::Room{ id: self.id, name });
+                        self.request(ctx, request, move |res, _, reply| {
+                            match res {
+                                Ok(key) => {
+                                    match key {
+                                        Some(x) => reply.text(format!("/room {:?} {:?}", name, x)),
+                                        None => reply.text("!!! cant get key"),
+                                    }
+                                }
+                                _ => reply.text("!!! somethig go wrong"),
                             }
-                            self.addr.send(server::Members{ room: self.room.clone() })
-                            .into_actor(self)
-                            .then(|res, _, ctx| {
+                        });
+                    } else {
+                        self.text(ctx, "!!! room name must be integer");
+                    }
+                }
+                "/id" => {
+                    if v.len() != 1 {
+                        self.text(ctx, "!!! syntax error");
+                        return;
+                    }
+                    self.text(ctx, format!("/id {:?}", self.id))
+                }
+                "/token" => {
+                    if v.len() != 1 {
+                        self.text(ctx, "!!! syntax error");
+                        return;
+                    }
+                    self.text(ctx, format!("/token {:?}", self.token))
+                }
+                "/resume" => {
+                    if v.len() != 2 {
+                        self.text(ctx, "!!! syntax error");
+                        return;
+                    }
+                    let data: Vec<&str> = v[1].splitn(2, ' ').collect();
+                    if let Some(resume_id) = data.first().and_then(|x| x.parse::<usize>().ok()) {
+                        if let Some(token) = data.get(1).and_then(|x| x.parse::<usize>().ok()) {
+                            // the session may live on another shard than the one made for this socket
+                            let shard = self.router.shard(resume_id).clone();
+                            let request = shard.send(server::Resume {
+                                id: self.id,
+                                resume_id,
+                                token,
+                                identity: self.identity.clone(),
+                                addr: ctx.address().recipient(),
+                                going_away: ctx.address().recipient(),
+                            });
+                            self.ordered(ctx, request, move |res, act, reply| {
                                 match res {
-                                    Ok(key) => {
-                                        ctx.text(format!("/members {:?}", key));
+                                    Ok(server::ResumeResult::Resumed) => {
+                                        act.id = resume_id;
+                                        act.token = token;
+                                        act.addr = shard;
+                                        reply.text(format!("/resumed {}", resume_id));
                                     }
-                                    _ => ctx.text("!!! somethig go wrong"),
+                                    Ok(server::ResumeResult::BadToken) => reply.text("!!! cant resume session"),
+                                    _ => reply.text("!!! somethig go wrong"),
                                 }
-                                fut::ready(())
+                            });
+                        } else {
+                            self.text(ctx, "!!! token must be integer");
+                        }
+                    } else {
+                        self.text(ctx, "!!! user id must be integer");
+                    }
+                }
+                "/members" => {
+                    if v.len() != 2 {
+                        self.text(ctx, "!!! syntax error");
+                        return;
+                    }
+                    if let Ok(room) = v[1].parse::<usize>() {
+                        let request = self.router.shard(room).send(server::Members{ id: self.id, room });
+                        self.request(ctx, request, move |res, _, reply| {
+                            match res {
+                                Ok(Some(ids)) => {
+                                    reply.text(format!("/members {} {:?}", room, ids));
+                                }
+                                Ok(None) => reply.text("!!! not a member of room"),
+                                _ => reply.text("!!! somethig go wrong"),
+                            }
+                        });
+                    } else {
+                        self.text(ctx, "!!! room name must be integer");
+                    }
+                }
+                "/who" => {
+                    if v.len() != 2 {
+                        self.text(ctx, "!!! syntax error");
+                        return;
+                    }
+                    if let Ok(room) = v[1].parse::<usize>() {
+                        let request = self.router.shard(room).send(server::Identities{ id: self.id, room });
+                        self.request(ctx, request, move |res, _, reply| {
+                            match res {
+                                Ok(Some(identities)) => {
+                                    reply.text(format!(
+                                        "/who {} {}",
+                                        room,
+                                        serde_json::to_string(&identities).unwrap_or_default()
+                                    ));
+                                }
+                                Ok(None) => reply.text("!!! not a member of room"),
+                                _ => reply.text("!!! somethig go wrong"),
+                            }
+                        });
+                    } else {
+                        self.text(ctx, "!!! room name must be integer");
+                    }
+                }
+                "/direct_message" | "/direct_message_ack" => {
+                    if v.len() != 2 {
+                        self.text(ctx, "!!! syntax error");
+                        return;
+                    }
+                    let data: Vec<&str> = v[1].splitn(3, ' ').collect();
+                    if let Some(room) = data.first().and_then(|x| x.parse::<usize>().ok()) {
+                        if let Some(id) = data.get(1).and_then(|x| x.parse::<usize>().ok()) {
+                            if let Some(mess) = data.get(2) {
+                                let request = self.router.shard(room).send(server::Direct{
+                                    room,
+                                    id_to: id,
+                                    id_from: self.id,
+                                    mess: mess.to_string(),
+                                    ack: v[0] == "/direct_message_ack",
+                                });
+                                self.request(ctx, request, move |res, _, reply| {
+                                    match res {
+                                        Ok(server::DirectResult::Send) => reply.text("/send"),
+                                        Ok(server::DirectResult::Pending(message_id)) => reply.text(format!("/pending {} {}", room, message_id)),
+                                        Ok(server::DirectResult::IdDontExist) => reply.text("!!! id not found"),
+                                        Ok(server::DirectResult::NotMember) => reply.text("!!! not a member of room"),
+                                        _ => reply.text("!!! somethig go wrong"),
+                                    }
+                                });
+                            }else{
+                                self.text(ctx, "!!! offer must be string");
+                            }
+                        }else{
+                            self.text(ctx, "!!! user id must be integer");
+                        }
+                    }else{
+                        self.text(ctx, "!!! room name must be integer");
+                    }
+                }
+                "/ack" => {
+                    if v.len() != 2 {
+                        self.text(ctx, "!!! syntax error");
+                        return;
+                    }
+                    if let Ok(message_id) = v[1].parse::<u64>() {
+                        self.addr.do_send(server::Ack {
+                            id: self.id,
+                            message_id,
+                        })
+                    } else {
+                        self.text(ctx, "!!! message id must be integer");
+                    }
+                }
+                "/message" => {
+                    if v.len() != 2 {
+                        self.text(ctx, "!!! syntax error");
+                        return;
+                    }
+                    let data: Vec<&str> = v[1].splitn(2, ' ').collect();
+                    if let Some(room) = data.first().and_then(|x| x.parse::<usize>().ok()) {
+                        if let Some(msg) = data.get(1) {
+                            // send message to chat server
+                            self.router.shard(room).do_send(server::ClientMessage {
+                                id: self.id,
+                                msg: msg.to_string(),
+                                room,
                             })
-                            .wait(ctx)
+                        } else {
+                            self.text(ctx, "!!! message must be string");
                         }
-                        "/direct_message" => {
-                            if v.len() != 2 {
-                                ctx.text("!!! syntax error");
-                                return;
+                    } else {
+                        self.text(ctx, "!!! room name must be integer");
+                    }
+                }
+                "/transfer_offer" => {
+                    if v.len() != 2 {
+                        self.text(ctx, "!!! syntax error");
+                        return;
+                    }
+                    let data: Vec<&str> = v[1].splitn(2, ' ').collect();
+                    if let Some(room) = data.first().and_then(|x| x.parse::<usize>().ok()) {
+                        match Offer::parse(data.get(1).unwrap_or(&"")) {
+                            Ok(offer) => {
+                                let request = self.router.shard(room).send(server::TransferOffer { id: self.id, room, offer });
+                                self.request(ctx, request, move |res, _, reply| {
+                                    match res {
+                                        Ok(server::TransferResult::Offered(transfer)) => reply.text(format!("/transfer_offered {} {}", room, transfer)),
+                                        Ok(server::TransferResult::NotMember) => reply.text("!!! not a member of room"),
+                                        Ok(server::TransferResult::IdDontExist) => reply.text("!!! id not found"),
+                                        _ => reply.text("!!! somethig go wrong"),
+                                    }
+                                });
                             }
-                            let data: Vec<&str> = v[1].splitn(2, ' ').collect();
-                            if let Some(id) = data.get(0).map_or(None, |x| x.parse::<usize>().ok()) {
-                                if let Some(mess) = data.get(1) {
-                                    self.addr.send(server::Direct{ 
-                                        room: self.room,
-                                        id_to: id,
-                                        id_from: self.id,
-                                        mess: mess.to_string()
-                                    })
-                                    .into_actor(self)
-                                    .then(|res, _, ctx| {
-                                        match res {
-                                            Ok(server::DirectResult::Send) => ctx.text("/send"),
-                                            Ok(server::DirectResult::IdDontExist) => ctx.text("!!! id not found"),
-                                            _ => ctx.text("!!! somethig go wrong"),
-                                        }
-                                        fut::ready(())
-                                    })
-                                    .wait(ctx)
-                                }else{
-                                    ctx.text("!!! offer must be string");
+                            Err(err) => self.text(ctx, format!("!!! {}", err)),
+                        }
+                    } else {
+                        self.text(ctx, "!!! room name must be integer");
+                    }
+                }
+                "/transfer_accept" | "/transfer_reject" | "/transfer_progress" | "/transfer_complete" | "/transfer_cancel" => {
+                    if v.len() != 2 {
+                        self.text(ctx, "!!! syntax error");
+                        return;
+                    }
+                    let data: Vec<&str> = v[1].splitn(3, ' ').collect();
+                    if let Some(room) = data.first().and_then(|x| x.parse::<usize>().ok()) {
+                        if let Some(transfer) = data.get(1).and_then(|x| x.parse::<u64>().ok()) {
+                            let update = match v[0] {
+                                "/transfer_accept" => Update::Accept,
+                                "/transfer_reject" => Update::Reject,
+                                "/transfer_complete" => Update::Complete,
+                                "/transfer_cancel" => Update::Cancel,
+                                _ => match data.get(2).and_then(|x| x.parse::<u64>().ok()) {
+                                    Some(bytes) => Update::Progress(bytes),
+                                    None => {
+                                        self.text(ctx, "!!! transferred bytes must be integer");
+                                        return;
+                                    }
+                                },
+                            };
+                            let request = self.router.shard(room).send(server::TransferUpdate { id: self.id, room, transfer, update });
+                            self.request(ctx, request, |res, _, reply| {
+                                match res {
+                                    // the new state arrives as /transfer like for every other member
+                                    Ok(server::TransferResult::Updated) => (),
+                                    Ok(server::TransferResult::NotMember) => reply.text("!!! not a member of room"),
+                                    Ok(server::TransferResult::NotFound) => reply.text("!!! transfer not found"),
+                                    Ok(server::TransferResult::NotAllowed) => reply.text("!!! not allowed for this transfer"),
+                                    _ => reply.text("!!! somethig go wrong"),
+                                }
+                            });
+                        } else {
+                            self.text(ctx, "!!! transfer id must be integer");
+                        }
+                    } else {
+                        self.text(ctx, "!!! room name must be integer");
+                    }
+                }
+                "/state" => {
+                    if v.len() != 2 {
+                        self.text(ctx, "!!! syntax error");
+                        return;
+                    }
+                    let data: Vec<&str> = v[1].splitn(2, ' ').collect();
+                    if let Some(room) = data.first().and_then(|x| x.parse::<usize>().ok()) {
+                        let activity = match data.get(1) {
+                            Some(&"idle") => None,
+                            Some(name) => match server::Activity::parse(name) {
+                                Some(activity) => Some(activity),
+                                None => {
+                                    self.text(ctx, "!!! state must be typing, picking_files, uploading or idle");
                                     return;
                                 }
-                            }else{
-                                ctx.text("!!! user id must be integer");
+                            },
+                            None => {
+                                self.text(ctx, "!!! state must be typing, picking_files, uploading or idle");
                                 return;
                             }
-                        }
-                        _ => ctx.text(format!("!!! unknown command: {m:?}")),
+                        };
+                        // ephemeral state bypasses the chat path, nothing is sent back
+                        self.router.shard(room).do_send(server::Ephemeral {
+                            id: self.id,
+                            room,
+                            activity,
+                        })
+                    } else {
+                        self.text(ctx, "!!! room name must be integer");
                     }
-                } else {
-                    // send message to chat server
-                    self.addr.do_send(server::ClientMessage {
-                        id: self.id,
-                        msg: m.to_string(),
-                        room: self.room.clone(),
-                    })
                 }
+                _ => self.text(ctx, format!("!!! unknown command: {m:?}")),
             }
-            ws::Message::Binary(_) => println!("Unexpected binary"),
-            ws::Message::Close(reason) => {
-                ctx.close(reason);
-                self.addr.do_send(server::Disconnect { id: self.id, room: self.room.clone() });
-                ctx.stop();
-            }
-            ws::Message::Continuation(_) => {
-                self.addr.do_send(server::Disconnect { id: self.id, room: self.room.clone() });
-                ctx.stop();
-            }
-            ws::Message::Nop => (),
+        } else {
+            self.text(ctx, "!!! target room required, use /message <room> <text>");
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Requests;
+
+    #[test]
+    fn replies_go_out_in_command_order() {
+        let mut requests = Requests::default();
+        let (first, second, third) = (requests.open(), requests.open(), requests.open());
+
+        assert!(requests.answer(second, vec!["b".into()]).is_empty());
+        assert!(requests.answer(third, vec![]).is_empty());
+        assert_eq!(requests.answer(first, vec!["a".into()]), ["a", "b"]);
+        assert!(!requests.busy());
+
+        let fourth = requests.open();
+        assert_eq!(requests.answer(fourth, vec!["c".into(), "d".into()]), ["c", "d"]);
+    }
+}
@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::backplane::{node_of, NodeId};
+
+/// Rooms and the sessions in them, saved on shutdown so clients can `/resume` after a restart
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub sessions: Vec<SessionSnapshot>,
+    pub rooms: Vec<RoomSnapshot>,
+}
+
+impl Snapshot {
+    /// Take out the sessions and rooms that live on `node`
+    pub fn take_node(&mut self, node: NodeId) -> Snapshot {
+        let (sessions, others) = std::mem::take(&mut self.sessions)
+            .into_iter()
+            .partition(|session| node_of(session.id) == node);
+        self.sessions = others;
+        let (rooms, others) = std::mem::take(&mut self.rooms)
+            .into_iter()
+            .partition(|room| node_of(room.id) == node);
+        self.rooms = others;
+
+        Snapshot { sessions, rooms }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SessionSnapshot {
+    pub id: usize,
+    pub token: usize,
+    pub identity: Option<String>,
+    /// Rooms the session is a member of, the ones of other nodes only show up here
+    #[serde(default)]
+    pub rooms: Vec<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RoomSnapshot {
+    pub id: usize,
+    pub key: usize,
+    pub owner: usize,
+    pub members: Vec<usize>,
+    pub invites: Vec<usize>,
+    /// `manual`, `join` or seconds between rotations
+    pub rotation: String,
+    pub seq: u64,
+    pub history_len: usize,
+    /// seconds
+    pub history_age: u64,
+    pub history: Vec<EntrySnapshot>,
+    /// Client certificate subjects of members of other nodes
+    #[serde(default)]
+    pub identities: HashMap<usize, String>,
+    /// Milliseconds since the unix epoch
+    pub created: u128,
+    pub last_activity: u128,
+}
+
+/// One kept event of a room, see `history::Entry`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EntrySnapshot {
+    pub seq: u64,
+    pub id: u64,
+    pub timestamp: u128,
+    pub event: String,
+    pub sender: usize,
+    pub recipient: Option<usize>,
+    pub text: String,
+}
+
+/// Write the snapshot next to `path` first, a crash halfway never leaves a truncated one behind
+pub fn save(path: &Path, snapshot: &Snapshot) -> io::Result<()> {
+    let json = serde_json::to_vec(snapshot).map_err(io::Error::other)?;
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, json)?;
+    std::fs::rename(tmp, path)
+}
+
+/// Read and remove the snapshot, `None` when there is none.
+/// It is only good for the restart right after it was taken.
+pub fn take(path: &Path) -> io::Result<Option<Snapshot>> {
+    let json = match std::fs::read(path) {
+        Ok(json) => json,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let snapshot = serde_json::from_slice(&json).map_err(io::Error::other)?;
+    std::fs::remove_file(path)?;
+
+    Ok(Some(snapshot))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{save, take, RoomSnapshot, SessionSnapshot, Snapshot};
+    use crate::backplane::on_node;
+
+    #[test]
+    fn snapshot_is_read_once() {
+        let path = std::env::temp_dir().join(format!("peershare-snapshot-{}.json", std::process::id()));
+        let snapshot = Snapshot {
+            sessions: vec![SessionSnapshot {
+                id: 1,
+                token: 2,
+                identity: Some("CN=alice".to_owned()),
+                rooms: vec![3],
+            }],
+            rooms: vec![RoomSnapshot {
+                id: 3,
+                key: 4,
+                owner: 1,
+                members: vec![1],
+                invites: Vec::new(),
+                rotation: "join".to_owned(),
+                seq: 0,
+                history_len: 50,
+                history_age: 600,
+                history: Vec::new(),
+                identities: HashMap::new(),
+                created: 0,
+                last_activity: 0,
+            }],
+        };
+
+        save(&path, &snapshot).unwrap();
+        let restored = take(&path).unwrap().unwrap();
+        assert_eq!(restored.sessions[0].identity.as_deref(), Some("CN=alice"));
+        assert_eq!(restored.rooms[0].key, 4);
+        assert_eq!(restored.rooms[0].rotation, "join");
+
+        assert!(take(&path).unwrap().is_none());
+    }
+
+    #[test]
+    fn nodes_take_their_own_sessions() {
+        let session = |id| SessionSnapshot {
+            id,
+            token: 0,
+            identity: None,
+            rooms: Vec::new(),
+        };
+        let mut snapshot = Snapshot {
+            sessions: vec![session(on_node(1, 0)), session(on_node(2, 1)), session(on_node(3, 1))],
+            rooms: Vec::new(),
+        };
+
+        let taken = snapshot.take_node(1);
+        assert_eq!(taken.sessions.len(), 2);
+        assert_eq!(snapshot.sessions.len(), 1);
+        assert!(snapshot.take_node(2).sessions.is_empty());
+        assert_eq!(snapshot.take_node(0).sessions[0].id, 1);
+        assert!(snapshot.sessions.is_empty());
+    }
+}
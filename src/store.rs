@@ -0,0 +1,129 @@
+use std::fmt::Debug;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::snapshot::{RoomSnapshot, SessionSnapshot, Snapshot};
+
+/// Keeps rooms and sessions as they change, so they outlive the process
+pub trait RoomStore: Debug + Send {
+    fn save_room(&mut self, room: &RoomSnapshot) -> io::Result<()>;
+    fn remove_room(&mut self, id: usize) -> io::Result<()>;
+    fn save_session(&mut self, session: &SessionSnapshot) -> io::Result<()>;
+    fn remove_session(&mut self, id: usize) -> io::Result<()>;
+    /// Everything saved so far
+    fn load(&mut self) -> io::Result<Snapshot>;
+}
+
+/// Stores every room and session as a JSON file in a directory
+#[derive(Debug)]
+pub struct DirStore {
+    rooms: PathBuf,
+    sessions: PathBuf,
+}
+
+impl DirStore {
+    pub fn open(dir: &Path) -> io::Result<DirStore> {
+        let store = DirStore {
+            rooms: dir.join("rooms"),
+            sessions: dir.join("sessions"),
+        };
+        std::fs::create_dir_all(&store.rooms)?;
+        std::fs::create_dir_all(&store.sessions)?;
+
+        Ok(store)
+    }
+}
+
+impl RoomStore for DirStore {
+    fn save_room(&mut self, room: &RoomSnapshot) -> io::Result<()> {
+        write(&self.rooms, room.id, room)
+    }
+
+    fn remove_room(&mut self, id: usize) -> io::Result<()> {
+        remove(&self.rooms, id)
+    }
+
+    fn save_session(&mut self, session: &SessionSnapshot) -> io::Result<()> {
+        write(&self.sessions, session.id, session)
+    }
+
+    fn remove_session(&mut self, id: usize) -> io::Result<()> {
+        remove(&self.sessions, id)
+    }
+
+    fn load(&mut self) -> io::Result<Snapshot> {
+        Ok(Snapshot {
+            sessions: read_all(&self.sessions)?,
+            rooms: read_all(&self.rooms)?,
+        })
+    }
+}
+
+/// Written next to the final name first, a crash halfway leaves the old file intact
+fn write(dir: &Path, id: usize, value: &impl Serialize) -> io::Result<()> {
+    let json = serde_json::to_vec(value).map_err(io::Error::other)?;
+    let tmp = dir.join(format!("{}.tmp", id));
+    std::fs::write(&tmp, json)?;
+    std::fs::rename(tmp, dir.join(format!("{}.json", id)))
+}
+
+fn remove(dir: &Path, id: usize) -> io::Result<()> {
+    match std::fs::remove_file(dir.join(format!("{}.json", id))) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+/// Files that do not parse are skipped, one bad room should not cost all the others
+fn read_all<T: DeserializeOwned>(dir: &Path) -> io::Result<Vec<T>> {
+    let mut values = Vec::new();
+
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_none_or(|extension| extension != "json") {
+            continue;
+        }
+        match std::fs::read(&path).map(|json| serde_json::from_slice(&json)) {
+            Ok(Ok(value)) => values.push(value),
+            Ok(Err(err)) => log::warn!("skipping {}: {}", path.display(), err),
+            Err(err) => log::warn!("skipping {}: {}", path.display(), err),
+        }
+    }
+
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DirStore, RoomStore};
+    use crate::snapshot::SessionSnapshot;
+
+    #[test]
+    fn removed_sessions_are_not_loaded() {
+        let dir = std::env::temp_dir().join(format!("peershare-store-{}", std::process::id()));
+        let mut store = DirStore::open(&dir).unwrap();
+
+        for id in [1, 2] {
+            let session = SessionSnapshot {
+                id,
+                token: id * 10,
+                identity: None,
+                rooms: Vec::new(),
+            };
+            store.save_session(&session).unwrap();
+        }
+        store.remove_session(1).unwrap();
+        store.remove_session(3).unwrap();
+        std::fs::write(dir.join("sessions").join("4.json"), "{").unwrap();
+
+        let snapshot = store.load().unwrap();
+        assert_eq!(snapshot.sessions.len(), 1);
+        assert_eq!(snapshot.sessions[0].token, 20);
+        assert!(snapshot.rooms.is_empty());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,405 @@
+use std::any::Any;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use actix::prelude::*;
+use actix_tls::accept::openssl::TlsStream;
+use actix_web::dev::Extensions;
+use actix_web::rt::net::TcpStream;
+use openssl::asn1::Asn1Time;
+use openssl::ssl::{
+    select_next_proto, AlpnError, SslAcceptor, SslAcceptorBuilder, SslContext,
+    SslContextBuilder, SslFiletype, SslMethod, SslVerifyMode,
+};
+use openssl::x509::store::{X509Lookup, X509StoreBuilder};
+use openssl::x509::verify::X509VerifyFlags;
+use openssl::x509::{X509NameRef, X509};
+
+/// Protocols offered over ALPN, as the acceptor actix-web builds does
+const ALPN_PROTOCOLS: &[u8] = b"\x02h2\x08http/1.1";
+
+/// Files of the server certificate and of the optional client authentication
+#[derive(Debug, Clone)]
+pub struct TlsFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// CA bundle client certificates must be issued by, `None` does not ask for one
+    pub client_ca: Option<PathBuf>,
+    /// Revoked client certificates
+    pub client_crl: Option<PathBuf>,
+}
+
+impl TlsFiles {
+    fn paths(&self) -> impl Iterator<Item = &PathBuf> {
+        [
+            Some(&self.cert),
+            Some(&self.key),
+            self.client_ca.as_ref(),
+            self.client_crl.as_ref(),
+        ]
+        .into_iter()
+        .flatten()
+    }
+}
+
+/// Subject of the verified client certificate of a connection, e.g. `CN=alice,O=Example`
+#[derive(Debug, Clone)]
+pub struct PeerIdentity(pub String);
+
+/// Reload the certificate and key from disk
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Reload;
+
+/// Keeps the certificate handed out on new handshakes current.
+///
+/// The acceptor switches every handshake over to the latest context, so a reload
+/// only affects new connections and open ones stay up.
+pub struct CertWatcher {
+    files: TlsFiles,
+    current: Arc<RwLock<SslContext>>,
+    /// Modification times of the files last loaded
+    modified: Vec<Option<SystemTime>>,
+    /// How often the files are checked for changes, `None` only reloads on request
+    interval: Option<Duration>,
+}
+
+impl CertWatcher {
+    /// Load the certificate and key and build the acceptor to listen with
+    pub fn new(
+        files: TlsFiles,
+        interval: Option<Duration>,
+    ) -> io::Result<(SslAcceptorBuilder, CertWatcher)> {
+        let context = load(&files).map_err(io::Error::other)?;
+        let current = Arc::new(RwLock::new(context));
+
+        let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+        builder.set_private_key_file(&files.key, SslFiletype::PEM)?;
+        builder.set_certificate_chain_file(&files.cert)?;
+        // handshakes keep the verify mode of this context, the CAs they trust come from the current one
+        client_auth(&mut builder, &files).map_err(io::Error::other)?;
+        builder.set_servername_callback({
+            let current = current.clone();
+            move |ssl, _| {
+                if let Ok(context) = current.read() {
+                    if let Err(err) = ssl.set_ssl_context(&context) {
+                        log::error!("cant switch handshake to the current certificate: {}", err);
+                    }
+                }
+                Ok(())
+            }
+        });
+
+        let watcher = CertWatcher {
+            modified: modified(&files),
+            files,
+            current,
+            interval,
+        };
+
+        Ok((builder, watcher))
+    }
+}
+
+impl CertWatcher {
+    /// Reload once any of the files changed since they were last loaded
+    fn check(&mut self) {
+        if modified(&self.files) != self.modified {
+            self.reload();
+        }
+    }
+
+    fn reload(&mut self) {
+        self.modified = modified(&self.files);
+
+        match load(&self.files) {
+            Ok(context) => {
+                if let Ok(mut current) = self.current.write() {
+                    *current = context;
+                    log::info!("reloaded tls certificate {}", self.files.cert.display());
+                }
+            }
+            Err(err) => log::error!("keeping the old tls certificate: {}", err),
+        }
+    }
+}
+
+impl Actor for CertWatcher {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        if let Some(interval) = self.interval {
+            ctx.run_interval(interval, |act, _| act.check());
+        }
+    }
+}
+
+impl Handler<Reload> for CertWatcher {
+    type Result = ();
+
+    fn handle(&mut self, _: Reload, _: &mut Context<Self>) {
+        self.reload();
+    }
+}
+
+/// Remember the subject of the client certificate of a new connection, for `HttpServer::on_connect`
+pub fn peer_identity(conn: &dyn Any, ext: &mut Extensions) {
+    let Some(stream) = conn.downcast_ref::<TlsStream<TcpStream>>() else {
+        return;
+    };
+
+    if let Some(cert) = stream.ssl().peer_certificate() {
+        ext.insert(PeerIdentity(subject(cert.subject_name())));
+    }
+}
+
+/// Values are decoded in full and escaped like RFC 4514 does, so no value, with a NUL,
+/// a `,` or a `=` in it, makes two subjects the same identity
+fn subject(name: &X509NameRef) -> String {
+    name.entries()
+        .map(|entry| {
+            let key = entry.object().nid().short_name().unwrap_or("?");
+            let value = entry.data().to_string().unwrap_or_default();
+            format!("{}={}", key, escape(&value))
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn escape(value: &str) -> String {
+    let last = value.chars().count().saturating_sub(1);
+    let mut escaped = String::with_capacity(value.len());
+
+    for (index, c) in value.chars().enumerate() {
+        match c {
+            '\0' => escaped.push_str("\\00"),
+            '"' | '+' | ',' | ';' | '<' | '>' | '=' | '\\' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '#' if index == 0 => escaped.push_str("\\#"),
+            ' ' if index == 0 || index == last => escaped.push_str("\\ "),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+fn modified(files: &TlsFiles) -> Vec<Option<SystemTime>> {
+    files
+        .paths()
+        .map(|path| std::fs::metadata(path).and_then(|meta| meta.modified()).ok())
+        .collect()
+}
+
+fn bad(what: &str, path: &Path, err: &dyn std::fmt::Display) -> String {
+    format!("cant load {} {}: {}", what, path.display(), err)
+}
+
+/// Ask for a client certificate issued by the CA bundle and not revoked by the CRL
+fn client_auth(builder: &mut SslContextBuilder, files: &TlsFiles) -> Result<(), String> {
+    let Some(ca) = &files.client_ca else {
+        return Ok(());
+    };
+
+    let pem = std::fs::read(ca).map_err(|err| bad("tls_client_ca", ca, &err))?;
+    let certs = X509::stack_from_pem(&pem).map_err(|err| bad("tls_client_ca", ca, &err))?;
+    if certs.is_empty() {
+        return Err(bad("tls_client_ca", ca, &"no certificates"));
+    }
+
+    let mut store = X509StoreBuilder::new().map_err(|err| err.to_string())?;
+    for cert in certs {
+        builder
+            .add_client_ca(&cert)
+            .map_err(|err| bad("tls_client_ca", ca, &err))?;
+        store
+            .add_cert(cert)
+            .map_err(|err| bad("tls_client_ca", ca, &err))?;
+    }
+    if let Some(crl) = &files.client_crl {
+        store
+            .add_lookup(X509Lookup::file())
+            .and_then(|lookup| lookup.load_crl_file(crl, SslFiletype::PEM))
+            .map_err(|err| bad("tls_client_crl", crl, &err))?;
+        store
+            .set_flags(X509VerifyFlags::CRL_CHECK)
+            .map_err(|err| err.to_string())?;
+    }
+
+    builder
+        .set_verify_cert_store(store.build())
+        .map_err(|err| err.to_string())?;
+    builder.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+    // resumed sessions must come from the same context when peers are verified
+    builder
+        .set_session_id_context(b"peershare")
+        .map_err(|err| err.to_string())?;
+    Ok(())
+}
+
+/// Build a context for the certificate and key, rejecting ones that do not
+/// parse, do not belong together or already expired
+fn load(files: &TlsFiles) -> Result<SslContext, String> {
+    let (cert, key) = (&files.cert, &files.key);
+
+    let mut builder =
+        SslAcceptor::mozilla_intermediate(SslMethod::tls()).map_err(|err| err.to_string())?;
+    builder
+        .set_certificate_chain_file(cert)
+        .map_err(|err| bad("tls_cert", cert, &err))?;
+    builder
+        .set_private_key_file(key, SslFiletype::PEM)
+        .map_err(|err| bad("tls_key", key, &err))?;
+    builder
+        .check_private_key()
+        .map_err(|err| bad("tls_key", key, &err))?;
+    builder.set_alpn_select_callback(|_, client| {
+        select_next_proto(ALPN_PROTOCOLS, client).ok_or(AlpnError::NOACK)
+    });
+    client_auth(&mut builder, files)?;
+
+    let context = builder.build().into_context();
+    let now = Asn1Time::days_from_now(0).map_err(|err| err.to_string())?;
+    if context
+        .certificate()
+        .is_some_and(|certificate| certificate.not_after() < now)
+    {
+        return Err(bad("tls_cert", cert, &"certificate expired"));
+    }
+
+    Ok(context)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::path::{Path, PathBuf};
+    use std::time::{Duration, SystemTime};
+
+    use openssl::asn1::Asn1Time;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::{PKey, Private};
+    use openssl::x509::{X509Name, X509};
+
+    use super::{subject, CertWatcher, TlsFiles};
+
+    fn key() -> PKey<Private> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+    }
+
+    /// Self-signed certificate for `cn`
+    fn certificate(cn: &str, key: &PKey<Private>) -> Vec<u8> {
+        let mut name = X509Name::builder().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, cn).unwrap();
+        let name = name.build();
+
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        cert.sign(key, MessageDigest::sha256()).unwrap();
+        cert.build().to_pem().unwrap()
+    }
+
+    /// Replace a file, the `version`th write gets a later modification time than the ones before
+    fn write(path: &Path, contents: &[u8], version: u64) {
+        fs::write(path, contents).unwrap();
+        let modified = SystemTime::now() - Duration::from_secs(100) + Duration::from_secs(version);
+        File::options().write(true).open(path).unwrap().set_modified(modified).unwrap();
+    }
+
+    fn watch(dir: &Path, cn: &str) -> (CertWatcher, PathBuf, PathBuf) {
+        fs::create_dir_all(dir).unwrap();
+        let (cert, key_file) = (dir.join("cert.pem"), dir.join("key.pem"));
+        let key = key();
+        write(&cert, &certificate(cn, &key), 0);
+        write(&key_file, &key.private_key_to_pem_pkcs8().unwrap(), 0);
+
+        let files = TlsFiles {
+            cert: cert.clone(),
+            key: key_file.clone(),
+            client_ca: None,
+            client_crl: None,
+        };
+        let (_, watcher) = CertWatcher::new(files, None).unwrap();
+        (watcher, cert, key_file)
+    }
+
+    fn served(watcher: &CertWatcher) -> String {
+        let current = watcher.current.read().unwrap();
+        subject(current.certificate().unwrap().subject_name())
+    }
+
+    #[test]
+    fn changed_certificate_is_picked_up() {
+        let dir = std::env::temp_dir().join(format!("peershare-tls-reload-{}", std::process::id()));
+        let (mut watcher, cert, key_file) = watch(&dir, "old");
+        watcher.check();
+        assert_eq!(served(&watcher), "CN=old");
+
+        let key = key();
+        write(&cert, &certificate("new", &key), 1);
+        write(&key_file, &key.private_key_to_pem_pkcs8().unwrap(), 1);
+        watcher.check();
+        assert_eq!(served(&watcher), "CN=new");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn bad_replacement_keeps_the_old_certificate() {
+        let dir = std::env::temp_dir().join(format!("peershare-tls-bad-{}", std::process::id()));
+        let (mut watcher, cert, key_file) = watch(&dir, "old");
+
+        // a certificate for a key the server does not have
+        write(&cert, &certificate("other", &key()), 1);
+        watcher.check();
+        assert_eq!(served(&watcher), "CN=old");
+
+        write(&key_file, b"not a key", 2);
+        watcher.check();
+        assert_eq!(served(&watcher), "CN=old");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn subjects_keep_what_follows_a_nul() {
+        let name = |cn: &str| {
+            let mut name = X509Name::builder().unwrap();
+            name.append_entry_by_nid(Nid::ORGANIZATIONNAME, "peers").unwrap();
+            name.append_entry_by_nid(Nid::COMMONNAME, cn).unwrap();
+            subject(&name.build())
+        };
+
+        assert_eq!(name("alice"), "O=peers,CN=alice");
+        assert_eq!(name("alice\0x"), "O=peers,CN=alice\\00x");
+    }
+
+    #[test]
+    fn subject_values_are_escaped() {
+        let mut name = X509Name::builder().unwrap();
+        name.append_entry_by_nid(Nid::ORGANIZATIONNAME, "peers,CN=alice").unwrap();
+        let joined = subject(&name.build());
+
+        let mut name = X509Name::builder().unwrap();
+        name.append_entry_by_nid(Nid::ORGANIZATIONNAME, "peers").unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, "alice").unwrap();
+        assert_ne!(joined, subject(&name.build()));
+        assert_eq!(joined, "O=peers\\,CN\\=alice");
+
+        let mut name = X509Name::builder().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, "#a\\b+c ").unwrap();
+        assert_eq!(subject(&name.build()), "CN=\\#a\\\\b\\+c\\ ");
+    }
+}
@@ -0,0 +1,354 @@
+use std::collections::{BTreeMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+/// Most files a single offer may list
+const MAX_FILES: usize = 1000;
+
+/// Longest file name, MIME type or content hash accepted
+const MAX_FIELD_LEN: usize = 255;
+
+/// One file of a transfer as announced by the sender
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileInfo {
+    pub name: String,
+    pub size: u64,
+    pub mime: String,
+    /// Content hash the recipient can verify the file with, e.g. `sha256:<hex>`
+    pub hash: String,
+}
+
+/// Files a sender offers to one member of a room, or to all of them
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Offer {
+    /// `None` offers the files to every other member of the room
+    #[serde(default)]
+    pub to: Option<usize>,
+    pub files: Vec<FileInfo>,
+    /// Total size of the files, summed by `parse`
+    #[serde(default)]
+    pub size: u64,
+}
+
+impl Offer {
+    pub fn parse(json: &str) -> Result<Offer, &'static str> {
+        let mut offer: Offer = serde_json::from_str(json).map_err(|_| "offer must be json")?;
+
+        if offer.files.is_empty() || offer.files.len() > MAX_FILES {
+            return Err("offer must list between 1 and 1000 files");
+        }
+        let too_long = offer.files.iter().any(|file| {
+            file.name.is_empty()
+                || file.name.len() > MAX_FIELD_LEN
+                || file.mime.len() > MAX_FIELD_LEN
+                || file.hash.is_empty()
+                || file.hash.len() > MAX_FIELD_LEN
+        });
+        if too_long {
+            return Err("file name, mime and hash must be at most 255 characters");
+        }
+        offer.size = offer
+            .files
+            .iter()
+            .try_fold(0u64, |total, file| total.checked_add(file.size))
+            .ok_or("files are too large in total")?;
+
+        Ok(offer)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransferState {
+    Offered,
+    Accepted,
+    InProgress,
+    Complete,
+    Rejected,
+    Cancelled,
+}
+
+impl TransferState {
+    /// Whether the transfer is over and no longer tracked
+    pub fn is_final(self) -> bool {
+        matches!(
+            self,
+            TransferState::Complete | TransferState::Rejected | TransferState::Cancelled
+        )
+    }
+}
+
+/// How far one recipient got with a transfer
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct RecipientStatus {
+    pub state: TransferState,
+    /// Bytes the recipient reported as received
+    pub bytes: u64,
+}
+
+/// A transfer tracked by the chat server
+#[derive(Debug, Clone, Serialize)]
+pub struct Transfer {
+    pub id: u64,
+    pub sender: usize,
+    pub files: Vec<FileInfo>,
+    pub size: u64,
+    /// State of the transfer as a whole, final once every recipient is done with it
+    pub state: TransferState,
+    pub recipients: BTreeMap<usize, RecipientStatus>,
+}
+
+impl Transfer {
+    pub fn new(id: u64, sender: usize, recipients: HashSet<usize>, offer: Offer) -> Transfer {
+        let status = RecipientStatus {
+            state: TransferState::Offered,
+            bytes: 0,
+        };
+
+        Transfer {
+            id,
+            sender,
+            size: offer.size,
+            files: offer.files,
+            state: TransferState::Offered,
+            recipients: recipients.into_iter().map(|id| (id, status)).collect(),
+        }
+    }
+
+    pub fn involves(&self, id: usize) -> bool {
+        self.sender == id || self.recipients.contains_key(&id)
+    }
+
+    /// Recipients that got everything and recipients in total, e.g. 3 of 5
+    pub fn received(&self) -> (usize, usize) {
+        let complete = self
+            .recipients
+            .values()
+            .filter(|status| status.state == TransferState::Complete)
+            .count();
+
+        (complete, self.recipients.len())
+    }
+
+    /// Take a recipient out of the transfer, its share counts as cancelled
+    pub fn drop_recipient(&mut self, id: usize) {
+        if let Some(status) = self.recipients.get_mut(&id) {
+            if !status.state.is_final() {
+                status.state = TransferState::Cancelled;
+            }
+        }
+        self.refresh_state();
+    }
+
+    /// Cancel the shares of every recipient that is not done yet
+    pub fn cancel(&mut self) {
+        for status in self.recipients.values_mut() {
+            if !status.state.is_final() {
+                status.state = TransferState::Cancelled;
+            }
+        }
+        self.state = TransferState::Cancelled;
+    }
+
+    /// Derive the overall state from the recipients
+    fn refresh_state(&mut self) {
+        let states: Vec<TransferState> = self.recipients.values().map(|status| status.state).collect();
+
+        self.state = if states.iter().all(|state| state.is_final()) {
+            if states.contains(&TransferState::Complete) {
+                TransferState::Complete
+            } else if states.contains(&TransferState::Rejected) {
+                TransferState::Rejected
+            } else {
+                TransferState::Cancelled
+            }
+        } else if states.contains(&TransferState::InProgress) {
+            TransferState::InProgress
+        } else if states.contains(&TransferState::Accepted) {
+            TransferState::Accepted
+        } else {
+            TransferState::Offered
+        };
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+/// What a participant or moderator does with a transfer
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum Update {
+    Accept,
+    Reject,
+    Progress(u64),
+    Complete,
+    Cancel,
+}
+
+impl Update {
+    /// Apply the update made by session `by`, `moderator` tells whether it owns the room.
+    /// Recipients update their own share, the sender and moderators can only cancel the whole transfer.
+    /// Returns `false` when `by` may not make this update.
+    pub fn apply(self, transfer: &mut Transfer, by: usize, moderator: bool) -> bool {
+        if transfer.state.is_final() {
+            return false;
+        }
+
+        if let Update::Cancel = self {
+            if by == transfer.sender || moderator {
+                transfer.cancel();
+                return true;
+            }
+        }
+
+        let size = transfer.size;
+        let Some(status) = transfer.recipients.get_mut(&by) else {
+            return false;
+        };
+        if status.state.is_final() {
+            return false;
+        }
+
+        match self {
+            Update::Accept if status.state == TransferState::Offered => {
+                status.state = TransferState::Accepted;
+            }
+            Update::Reject if status.state == TransferState::Offered => {
+                status.state = TransferState::Rejected;
+            }
+            Update::Progress(bytes) if status.state != TransferState::Offered => {
+                status.state = TransferState::InProgress;
+                status.bytes = bytes.min(size);
+            }
+            Update::Complete if status.state != TransferState::Offered => {
+                status.state = TransferState::Complete;
+                status.bytes = size;
+            }
+            Update::Cancel => {
+                status.state = TransferState::Cancelled;
+            }
+            _ => return false,
+        }
+
+        transfer.refresh_state();
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SENDER: usize = 1;
+
+    fn transfer(recipients: &[usize]) -> Transfer {
+        let offer = Offer::parse(r#"{"files":[{"name":"a.txt","size":100,"mime":"text/plain","hash":"sha256:00"}]}"#).unwrap();
+        Transfer::new(7, SENDER, recipients.iter().copied().collect(), offer)
+    }
+
+    fn share(transfer: &Transfer, id: usize) -> TransferState {
+        transfer.recipients[&id].state
+    }
+
+    #[test]
+    fn offer_size_is_summed_without_overflow() {
+        let file = |size: u64| format!(r#"{{"name":"a","size":{},"mime":"","hash":"h"}}"#, size);
+
+        let offer = Offer::parse(&format!(r#"{{"files":[{},{}],"size":1}}"#, file(2), file(3))).unwrap();
+        assert_eq!(offer.size, 5);
+
+        let files = format!(r#"{{"files":[{},{}]}}"#, file(u64::MAX), file(u64::MAX));
+        assert_eq!(Offer::parse(&files).unwrap_err(), "files are too large in total");
+    }
+
+    #[test]
+    fn recipient_accepts_before_making_progress() {
+        let mut transfer = transfer(&[2]);
+
+        assert!(!Update::Progress(10).apply(&mut transfer, 2, false));
+        assert!(!Update::Complete.apply(&mut transfer, 2, false));
+        assert!(Update::Accept.apply(&mut transfer, 2, false));
+        assert!(!Update::Accept.apply(&mut transfer, 2, false));
+        assert!(!Update::Reject.apply(&mut transfer, 2, false));
+        assert_eq!(transfer.state, TransferState::Accepted);
+
+        assert!(Update::Progress(500).apply(&mut transfer, 2, false));
+        assert_eq!(transfer.recipients[&2].bytes, 100);
+        assert_eq!(transfer.state, TransferState::InProgress);
+
+        assert!(Update::Complete.apply(&mut transfer, 2, false));
+        assert_eq!(transfer.state, TransferState::Complete);
+        assert!(transfer.state.is_final());
+        assert!(!Update::Cancel.apply(&mut transfer, SENDER, false));
+    }
+
+    #[test]
+    fn only_sender_and_moderators_cancel_the_whole_transfer() {
+        let mut transfer = transfer(&[2, 3]);
+
+        assert!(!Update::Cancel.apply(&mut transfer, 9, false));
+        assert!(!Update::Accept.apply(&mut transfer, SENDER, false));
+
+        assert!(Update::Cancel.apply(&mut transfer, 2, false));
+        assert_eq!(share(&transfer, 2), TransferState::Cancelled);
+        assert_eq!(transfer.state, TransferState::Offered);
+
+        assert!(Update::Cancel.apply(&mut transfer, 9, true));
+        assert_eq!(share(&transfer, 3), TransferState::Cancelled);
+        assert_eq!(transfer.state, TransferState::Cancelled);
+    }
+
+    #[test]
+    fn sender_leaving_cancels_recipient_leaving_drops_one_share() {
+        let mut recipient_left = transfer(&[2, 3]);
+        recipient_left.drop_recipient(2);
+        assert_eq!(share(&recipient_left, 2), TransferState::Cancelled);
+        assert_eq!(recipient_left.state, TransferState::Offered);
+        assert!(recipient_left.involves(SENDER) && !recipient_left.involves(9));
+
+        let mut sender_left = transfer(&[2, 3]);
+        assert!(Update::Accept.apply(&mut sender_left, 2, false));
+        assert!(Update::Complete.apply(&mut sender_left, 2, false));
+        sender_left.cancel();
+        assert_eq!(share(&sender_left, 2), TransferState::Complete);
+        assert_eq!(share(&sender_left, 3), TransferState::Cancelled);
+        assert_eq!(sender_left.state, TransferState::Cancelled);
+    }
+
+    #[test]
+    fn recipients_update_only_their_own_share() {
+        let mut transfer = transfer(&[2, 3, 4]);
+
+        assert!(Update::Accept.apply(&mut transfer, 2, false));
+        assert!(Update::Reject.apply(&mut transfer, 3, false));
+        assert_eq!(share(&transfer, 2), TransferState::Accepted);
+        assert_eq!(share(&transfer, 3), TransferState::Rejected);
+        assert_eq!(share(&transfer, 4), TransferState::Offered);
+        assert_eq!(transfer.state, TransferState::Accepted);
+
+        assert!(!Update::Accept.apply(&mut transfer, 3, false));
+        assert!(Update::Progress(40).apply(&mut transfer, 2, false));
+        assert_eq!(transfer.recipients[&4].bytes, 0);
+        assert_eq!(transfer.state, TransferState::InProgress);
+    }
+
+    #[test]
+    fn transfer_is_final_once_every_recipient_is() {
+        let mut completed = transfer(&[2, 3, 4]);
+
+        assert!(Update::Accept.apply(&mut completed, 2, false));
+        assert!(Update::Complete.apply(&mut completed, 2, false));
+        assert!(Update::Reject.apply(&mut completed, 3, false));
+        assert!(!completed.state.is_final());
+        assert_eq!(completed.received(), (1, 3));
+
+        completed.drop_recipient(4);
+        assert_eq!(completed.state, TransferState::Complete);
+
+        let mut rejected = transfer(&[2, 3]);
+        assert!(Update::Reject.apply(&mut rejected, 2, false));
+        assert!(Update::Cancel.apply(&mut rejected, 3, false));
+        assert_eq!(rejected.state, TransferState::Rejected);
+    }
+}
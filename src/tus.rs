@@ -0,0 +1,423 @@
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+
+use actix::*;
+use actix_web::{web, HttpRequest, HttpResponse};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures_util::StreamExt;
+use serde::Deserialize;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+use crate::reserr::ResErr;
+use crate::router::Router;
+use crate::routes::{blocking, Credentials};
+use crate::server;
+use crate::uploads::{
+    self, AbortPatch, Access, BeginPatch, Create, EndPatch, Partial, Status, StoreError,
+    Terminate, UploadConfig, UploadStore,
+};
+
+/// Protocol version every request and response carries
+pub const TUS_VERSION: &str = "1.0.0";
+
+const TUS_EXTENSIONS: &str = "creation,checksum,termination";
+
+const CHECKSUM_ALGORITHMS: &str = "sha1,sha256";
+
+const OFFSET_CONTENT_TYPE: &str = "application/offset+octet-stream";
+
+#[derive(Deserialize)]
+pub struct TusQuery {
+    /// Room the upload is for, only needed on creation
+    room: Option<usize>,
+}
+
+/// Hash of one PATCH request body for the checksum extension
+enum ChunkHasher {
+    Sha1(Sha1),
+    Sha256(Sha256),
+}
+
+impl ChunkHasher {
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            ChunkHasher::Sha1(hasher) => hasher.update(data),
+            ChunkHasher::Sha256(hasher) => hasher.update(data),
+        }
+    }
+
+    fn finalize(self) -> Vec<u8> {
+        match self {
+            ChunkHasher::Sha1(hasher) => hasher.finalize().to_vec(),
+            ChunkHasher::Sha256(hasher) => hasher.finalize().to_vec(),
+        }
+    }
+}
+
+fn header<'a>(req: &'a HttpRequest, name: &str) -> Option<&'a str> {
+    req.headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+}
+
+/// Requests must speak the protocol version we do
+fn check_version(req: &HttpRequest) -> Result<(), ResErr> {
+    match header(req, "Tus-Resumable") {
+        Some(TUS_VERSION) => Ok(()),
+        _ => Err(ResErr::PreconditionFailed("unsupported tus version")),
+    }
+}
+
+/// `Upload-Metadata` is a comma separated list of keys and base64 encoded values
+fn metadata(raw: &str) -> HashMap<String, String> {
+    raw.split(',')
+        .filter_map(|pair| {
+            let mut parts = pair.trim().splitn(2, ' ');
+            let key = parts.next().filter(|key| !key.is_empty())?;
+            let value = parts
+                .next()
+                .and_then(|value| STANDARD.decode(value.trim()).ok())
+                .and_then(|value| String::from_utf8(value).ok())
+                .unwrap_or_default();
+
+            Some((key.to_owned(), value))
+        })
+        .collect()
+}
+
+/// `Upload-Checksum: <algorithm> <base64 digest>`
+fn checksum(req: &HttpRequest) -> Result<Option<(ChunkHasher, Vec<u8>)>, ResErr> {
+    let Some(raw) = header(req, "Upload-Checksum") else {
+        return Ok(None);
+    };
+    let Some((algorithm, digest)) = raw.split_once(' ') else {
+        return Err(ResErr::BadClientData("bad checksum"));
+    };
+    let digest = STANDARD
+        .decode(digest.trim())
+        .map_err(|_| ResErr::BadClientData("bad checksum"))?;
+
+    let hasher = match algorithm {
+        "sha1" => ChunkHasher::Sha1(Sha1::new()),
+        "sha256" => ChunkHasher::Sha256(Sha256::new()),
+        _ => return Err(ResErr::BadClientData("unsupported checksum algorithm")),
+    };
+
+    Ok(Some((hasher, digest)))
+}
+
+fn store_error(err: StoreError) -> ResErr {
+    match err {
+        StoreError::QuotaExceeded => ResErr::InsufficientStorage("upload quota exceeded"),
+        StoreError::NotFound => ResErr::NotFound("upload not found"),
+        StoreError::Conflict => ResErr::Conflict("offset does not match the upload"),
+        StoreError::Io => ResErr::InternalError("cant store file"),
+    }
+}
+
+async fn authorize_session(
+    srv: &Router,
+    credentials: &Credentials,
+    room: usize,
+) -> Result<(), ResErr> {
+    let authorized = srv
+        .shard(credentials.session)
+        .send(server::Authorize {
+            id: credentials.session,
+            token: credentials.token,
+            room,
+        })
+        .await
+        .map_err(|_| ResErr::InternalError("chat server unavailable"))?;
+
+    if authorized {
+        Ok(())
+    } else {
+        Err(ResErr::Forbidden("not a member of the room"))
+    }
+}
+
+/// Only the session that created an upload may touch it, and only while it is in the room
+async fn authorize_upload(
+    srv: &Router,
+    store: &Addr<UploadStore>,
+    id: u64,
+    req: &HttpRequest,
+) -> Result<Partial, ResErr> {
+    let credentials = Credentials::required(req)?;
+    let partial = store
+        .send(Status { id })
+        .await
+        .map_err(|_| ResErr::InternalError("upload store unavailable"))?
+        .ok_or(ResErr::NotFound("upload not found"))?;
+
+    if partial.uploader != credentials.session {
+        return Err(ResErr::Forbidden("not your upload"));
+    }
+    authorize_session(srv, &credentials, partial.room).await?;
+
+    Ok(partial)
+}
+
+/// Tell clients what the server supports
+pub async fn options_route(config: web::Data<UploadConfig>) -> HttpResponse {
+    HttpResponse::NoContent()
+        .insert_header(("Tus-Version", TUS_VERSION))
+        .insert_header(("Tus-Extension", TUS_EXTENSIONS))
+        .insert_header(("Tus-Max-Size", config.max_size.to_string()))
+        .insert_header(("Tus-Checksum-Algorithm", CHECKSUM_ALGORITHMS))
+        .finish()
+}
+
+/// Creation extension, announce an upload and get the url to send it to
+pub async fn create_route(
+    req: HttpRequest,
+    query: web::Query<TusQuery>,
+    srv: web::Data<Router>,
+    store: web::Data<Addr<UploadStore>>,
+    config: web::Data<UploadConfig>,
+) -> Result<HttpResponse, ResErr> {
+    check_version(&req)?;
+
+    let length = header(&req, "Upload-Length")
+        .and_then(|length| length.parse::<u64>().ok())
+        .ok_or(ResErr::BadClientData("upload length required"))?;
+    if length > config.max_size {
+        return Err(ResErr::PayloadTooLarge("file too large"));
+    }
+
+    let raw = header(&req, "Upload-Metadata").unwrap_or_default();
+    let fields = metadata(raw);
+    let name = fields
+        .get("filename")
+        .filter(|name| !name.is_empty() && name.len() <= 255 && !name.contains(['/', '\\']))
+        .ok_or(ResErr::BadClientData("bad file name"))?;
+    let access = match fields.get("access").map(String::as_str) {
+        None | Some("room") => Access::Room,
+        Some("link") => Access::Link,
+        Some(_) => return Err(ResErr::BadClientData("access must be room or link")),
+    };
+
+    let room = query.room.ok_or(ResErr::BadClientData("room required"))?;
+    let credentials = Credentials::required(&req)?;
+    authorize_session(&srv, &credentials, room).await?;
+
+    let id = store
+        .send(Create {
+            room,
+            uploader: credentials.session,
+            name: name.to_owned(),
+            access,
+            length,
+            metadata: raw.to_owned(),
+        })
+        .await
+        .map_err(|_| ResErr::InternalError("upload store unavailable"))?
+        .map_err(store_error)?;
+
+    Ok(HttpResponse::Created()
+        .insert_header(("Location", format!("/tus/{}", id)))
+        .finish())
+}
+
+/// How much of the upload the server has
+pub async fn head_route(
+    req: HttpRequest,
+    id: web::Path<u64>,
+    srv: web::Data<Router>,
+    store: web::Data<Addr<UploadStore>>,
+) -> Result<HttpResponse, ResErr> {
+    check_version(&req)?;
+    let partial = authorize_upload(&srv, &store, id.into_inner(), &req).await?;
+
+    let mut res = HttpResponse::Ok();
+    res.insert_header(("Upload-Offset", partial.offset.to_string()))
+        .insert_header(("Upload-Length", partial.length.to_string()))
+        .insert_header(("Cache-Control", "no-store"));
+    if !partial.metadata.is_empty() {
+        res.insert_header(("Upload-Metadata", partial.metadata));
+    }
+
+    Ok(res.finish())
+}
+
+/// Append the request body at `Upload-Offset`. Without a checksum whatever arrived is kept,
+/// so an interrupted request can be resumed, with one the chunk is kept only if it matches.
+pub async fn patch_route(
+    req: HttpRequest,
+    id: web::Path<u64>,
+    mut payload: web::Payload,
+    srv: web::Data<Router>,
+    store: web::Data<Addr<UploadStore>>,
+    config: web::Data<UploadConfig>,
+) -> Result<HttpResponse, ResErr> {
+    check_version(&req)?;
+    if header(&req, "Content-Type") != Some(OFFSET_CONTENT_TYPE) {
+        return Err(ResErr::UnsupportedMediaType(
+            "content type must be application/offset+octet-stream",
+        ));
+    }
+    let offset = header(&req, "Upload-Offset")
+        .and_then(|offset| offset.parse::<u64>().ok())
+        .ok_or(ResErr::BadClientData("upload offset required"))?;
+    let mut checksum = checksum(&req)?;
+
+    let id = id.into_inner();
+    let partial = authorize_upload(&srv, &store, id, &req).await?;
+    let mut hasher = store
+        .send(BeginPatch { id, offset })
+        .await
+        .map_err(|_| ResErr::InternalError("upload store unavailable"))?
+        .map_err(store_error)?;
+    let mut guard = PatchGuard {
+        store: store.get_ref().clone(),
+        id,
+        offset,
+        ended: false,
+    };
+    let before = hasher.clone();
+
+    let path = uploads::part_path(&config.dir, id);
+    let mut end = offset;
+    let mut written = Ok(());
+    let opened = blocking({
+        let path = path.clone();
+        move || OpenOptions::new().append(true).open(path)
+    })
+    .await;
+    match opened {
+        Err(err) => written = Err(err),
+        Ok(mut file) => {
+            while let Some(chunk) = payload.next().await {
+                let Ok(chunk) = chunk else {
+                    written = Err(ResErr::BadClientData("upload interrupted"));
+                    break;
+                };
+                if end + chunk.len() as u64 > partial.length {
+                    written = Err(ResErr::PayloadTooLarge("chunk exceeds upload length"));
+                    break;
+                }
+                let write = {
+                    let chunk = chunk.clone();
+                    blocking(move || file.write_all(&chunk).map(|_| file))
+                };
+                match write.await {
+                    Ok(next) => file = next,
+                    Err(err) => {
+                        written = Err(err);
+                        break;
+                    }
+                }
+
+                end += chunk.len() as u64;
+                hasher.update(&chunk);
+                if let Some((chunk_hasher, _)) = checksum.as_mut() {
+                    chunk_hasher.update(&chunk);
+                }
+            }
+        }
+    }
+
+    if let Some((chunk_hasher, expected)) = checksum {
+        if written.is_ok() && chunk_hasher.finalize() != expected {
+            written = Err(ResErr::ChecksumMismatch("checksum mismatch"));
+        }
+        if written.is_err() {
+            end = offset;
+            hasher = before;
+            let discarded = blocking({
+                let path = path.clone();
+                move || OpenOptions::new().write(true).open(path)?.set_len(offset)
+            })
+            .await;
+            if discarded.is_err() {
+                log::error!("cant discard chunk of upload {}", id);
+            }
+        }
+    }
+
+    let finished = store
+        .send(EndPatch {
+            id,
+            offset: end,
+            hasher,
+        })
+        .await;
+    guard.ended = true;
+    let finished = finished
+        .map_err(|_| ResErr::InternalError("upload store unavailable"))?
+        .map_err(store_error)?;
+    written?;
+
+    if let Some(upload) = finished {
+        srv.shard(upload.room).do_send(server::UploadShared {
+            id: upload.uploader,
+            room: upload.room,
+            upload: serde_json::to_string(&upload).unwrap_or_default(),
+        });
+    }
+
+    Ok(HttpResponse::NoContent()
+        .insert_header(("Upload-Offset", end.to_string()))
+        .finish())
+}
+
+/// Hands an upload claimed with `BeginPatch` back to the store when the PATCH is dropped
+/// before `EndPatch`, e.g. because the client went away, so it does not stay busy until it expires
+struct PatchGuard {
+    store: Addr<UploadStore>,
+    id: u64,
+    /// where the PATCH started
+    offset: u64,
+    ended: bool,
+}
+
+impl Drop for PatchGuard {
+    fn drop(&mut self) {
+        if !self.ended {
+            self.store.do_send(AbortPatch {
+                id: self.id,
+                offset: self.offset,
+            });
+        }
+    }
+}
+
+/// Termination extension, abort an upload
+pub async fn delete_route(
+    req: HttpRequest,
+    id: web::Path<u64>,
+    srv: web::Data<Router>,
+    store: web::Data<Addr<UploadStore>>,
+) -> Result<HttpResponse, ResErr> {
+    check_version(&req)?;
+    let id = id.into_inner();
+    authorize_upload(&srv, &store, id, &req).await?;
+
+    let terminated = store
+        .send(Terminate { id })
+        .await
+        .map_err(|_| ResErr::InternalError("upload store unavailable"))?;
+
+    if terminated {
+        Ok(HttpResponse::NoContent().finish())
+    } else {
+        Err(ResErr::NotFound("upload not found"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::metadata;
+
+    #[test]
+    fn metadata_decodes_values_and_keeps_empty_keys() {
+        let fields = metadata("filename d29ybGRfZG9taW5hdGlvbl9wbGFuLnBkZg==, is_confidential");
+
+        assert_eq!(fields["filename"], "world_domination_plan.pdf");
+        assert_eq!(fields["is_confidential"], "");
+        assert_eq!(fields.len(), 2);
+    }
+}
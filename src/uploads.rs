@@ -0,0 +1,595 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use actix::prelude::*;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// How often expired uploads are deleted
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Limits of the store-and-forward upload endpoint
+#[derive(Debug, Clone)]
+pub struct UploadConfig {
+    /// Directory the uploaded files are kept in
+    pub dir: PathBuf,
+    /// Largest accepted file in bytes
+    pub max_size: u64,
+    /// Longest time a file is kept, uploaders may ask for less
+    pub ttl: Duration,
+    /// Most downloads of one file, uploaders may ask for fewer
+    pub max_downloads: u32,
+    /// Most bytes kept on disk, finished and unfinished uploads together
+    pub quota: u64,
+    /// Most bytes one session may keep on disk
+    pub session_quota: u64,
+}
+
+/// Who may download an upload
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Access {
+    /// Members of the room it was uploaded to
+    Room,
+    /// Anyone holding the download key, e.g. someone invited to the room who is offline
+    Link,
+}
+
+/// A file kept on disk for recipients who were offline
+#[derive(Debug, Clone, Serialize)]
+pub struct Upload {
+    pub id: u64,
+    pub room: usize,
+    pub uploader: usize,
+    pub name: String,
+    pub size: u64,
+    /// `sha256:<hex>` of the content
+    pub hash: String,
+    pub access: Access,
+    /// Relative link the file can be downloaded from
+    pub link: String,
+    pub downloads_left: u32,
+    #[serde(skip)]
+    pub key: u64,
+    #[serde(skip)]
+    pub expires: Instant,
+}
+
+impl Upload {
+    pub fn path(&self, dir: &std::path::Path) -> PathBuf {
+        dir.join(self.id.to_string())
+    }
+}
+
+/// Relative link an upload is downloaded from, link access carries the key
+pub fn link(id: u64, access: Access, key: u64) -> String {
+    match access {
+        Access::Room => format!("/files/{}", id),
+        Access::Link => format!("/files/{}?key={}", id, key),
+    }
+}
+
+/// Where the data of an unfinished upload is written to
+pub fn part_path(dir: &std::path::Path, id: u64) -> PathBuf {
+    dir.join(format!("{}.part", id))
+}
+
+/// A resumable upload that is still being written
+#[derive(Debug, Clone)]
+pub struct Partial {
+    pub id: u64,
+    pub room: usize,
+    pub uploader: usize,
+    pub name: String,
+    pub access: Access,
+    /// Size of the whole file
+    pub length: u64,
+    /// Bytes received so far
+    pub offset: u64,
+    /// Metadata as the client sent it on creation
+    pub metadata: String,
+    /// Hash of the bytes received so far
+    hasher: Sha256,
+    /// A PATCH is writing to the upload right now
+    busy: bool,
+    expires: Instant,
+}
+
+#[derive(Debug)]
+pub enum StoreError {
+    QuotaExceeded,
+    NotFound,
+    /// The client is not at the offset of the upload, or another request is writing to it
+    Conflict,
+    Io,
+}
+
+/// Hold quota for a file of at most `size` bytes before it is written,
+/// until it is registered with `Store` or given back with `Release`
+pub struct Reserve {
+    pub id: u64,
+    pub uploader: usize,
+    pub size: u64,
+}
+
+impl actix::Message for Reserve {
+    type Result = Result<(), StoreError>;
+}
+
+/// Register a file that was fully written to disk in place of its reservation,
+/// fails when it is over quota
+pub struct Store(pub Upload);
+
+impl actix::Message for Store {
+    type Result = Result<(), StoreError>;
+}
+
+/// Give back the quota of a reserved file that was not stored and delete what was written of it
+pub struct Release {
+    pub id: u64,
+}
+
+impl actix::Message for Release {
+    type Result = ();
+}
+
+/// Start a resumable upload of `length` bytes
+pub struct Create {
+    pub room: usize,
+    pub uploader: usize,
+    pub name: String,
+    pub access: Access,
+    pub length: u64,
+    pub metadata: String,
+}
+
+impl actix::Message for Create {
+    /// id of the new upload
+    type Result = Result<u64, StoreError>;
+}
+
+/// Current state of a resumable upload
+pub struct Status {
+    pub id: u64,
+}
+
+impl actix::Message for Status {
+    type Result = Option<Partial>;
+}
+
+/// Claim a resumable upload for writing at `offset`
+pub struct BeginPatch {
+    pub id: u64,
+    pub offset: u64,
+}
+
+impl actix::Message for BeginPatch {
+    /// Hash of the bytes received so far, to continue with
+    type Result = Result<Sha256, StoreError>;
+}
+
+/// Release a resumable upload after writing up to `offset`,
+/// the finished upload once every byte arrived
+pub struct EndPatch {
+    pub id: u64,
+    pub offset: u64,
+    pub hasher: Sha256,
+}
+
+impl actix::Message for EndPatch {
+    type Result = Result<Option<Upload>, StoreError>;
+}
+
+/// Release a resumable upload whose PATCH went away before `EndPatch`, e.g. because
+/// the client disconnected, dropping whatever it wrote after `offset`
+pub struct AbortPatch {
+    pub id: u64,
+    pub offset: u64,
+}
+
+impl actix::Message for AbortPatch {
+    type Result = ();
+}
+
+/// Abort a resumable upload and delete what was received
+pub struct Terminate {
+    pub id: u64,
+}
+
+impl actix::Message for Terminate {
+    type Result = bool;
+}
+
+/// Look up an upload without counting a download
+pub struct Lookup {
+    pub id: u64,
+}
+
+impl actix::Message for Lookup {
+    type Result = Option<Upload>;
+}
+
+/// Look up an upload for a download and count it, `None` once it expired or is used up
+pub struct Fetch {
+    pub id: u64,
+}
+
+impl actix::Message for Fetch {
+    type Result = Option<Upload>;
+}
+
+/// Keeps track of uploaded files and deletes them when they expire
+#[derive(Debug)]
+pub struct UploadStore {
+    config: UploadConfig,
+    uploads: HashMap<u64, Upload>,
+    partials: HashMap<u64, Partial>,
+    /// Uploader and size of files being written in one request, by upload id
+    reserved: HashMap<u64, (usize, u64)>,
+}
+
+impl UploadStore {
+    pub fn new(config: UploadConfig) -> UploadStore {
+        UploadStore {
+            config,
+            uploads: HashMap::new(),
+            partials: HashMap::new(),
+            reserved: HashMap::new(),
+        }
+    }
+}
+
+impl UploadStore {
+    fn remove(&mut self, id: u64) {
+        if let Some(upload) = self.uploads.remove(&id) {
+            if let Err(err) = std::fs::remove_file(upload.path(&self.config.dir)) {
+                log::warn!("cant remove upload {}: {}", id, err);
+            }
+        }
+    }
+}
+
+impl UploadStore {
+    fn remove_partial(&mut self, id: u64) {
+        if self.partials.remove(&id).is_some() {
+            if let Err(err) = std::fs::remove_file(part_path(&self.config.dir, id)) {
+                log::warn!("cant remove unfinished upload {}: {}", id, err);
+            }
+        }
+    }
+}
+
+impl UploadStore {
+    /// Whether `size` more bytes by `uploader` fit into the quotas.
+    /// Unfinished uploads count with their full length, reserved files with their reservation.
+    fn fits(&self, uploader: usize, size: u64) -> bool {
+        let stored = self
+            .uploads
+            .values()
+            .map(|upload| (upload.uploader, upload.size));
+        let partial = self
+            .partials
+            .values()
+            .map(|partial| (partial.uploader, partial.length));
+        let reserved = self.reserved.values().copied();
+
+        let (total, own) = stored
+            .chain(partial)
+            .chain(reserved)
+            .fold((0u64, 0u64), |(total, own), (by, size)| {
+                (total + size, if by == uploader { own + size } else { own })
+            });
+
+        total + size <= self.config.quota && own + size <= self.config.session_quota
+    }
+}
+
+impl Actor for UploadStore {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(SWEEP_INTERVAL, |act, _| {
+            let now = Instant::now();
+            let expired: Vec<u64> = act
+                .uploads
+                .values()
+                .filter(|upload| upload.expires <= now)
+                .map(|upload| upload.id)
+                .collect();
+
+            for id in expired {
+                act.remove(id);
+            }
+
+            let abandoned: Vec<u64> = act
+                .partials
+                .values()
+                .filter(|partial| partial.expires <= now)
+                .map(|partial| partial.id)
+                .collect();
+
+            for id in abandoned {
+                act.remove_partial(id);
+            }
+        });
+    }
+}
+
+impl Handler<Lookup> for UploadStore {
+    type Result = MessageResult<Lookup>;
+
+    fn handle(&mut self, msg: Lookup, _: &mut Context<Self>) -> Self::Result {
+        let now = Instant::now();
+
+        MessageResult(
+            self.uploads
+                .get(&msg.id)
+                .filter(|upload| upload.expires > now && upload.downloads_left > 0)
+                .cloned(),
+        )
+    }
+}
+
+impl Handler<Reserve> for UploadStore {
+    type Result = Result<(), StoreError>;
+
+    fn handle(&mut self, msg: Reserve, _: &mut Context<Self>) -> Self::Result {
+        if !self.fits(msg.uploader, msg.size) {
+            return Err(StoreError::QuotaExceeded);
+        }
+
+        self.reserved.insert(msg.id, (msg.uploader, msg.size));
+        Ok(())
+    }
+}
+
+impl Handler<Store> for UploadStore {
+    type Result = Result<(), StoreError>;
+
+    fn handle(&mut self, msg: Store, _: &mut Context<Self>) -> Self::Result {
+        self.reserved.remove(&msg.0.id);
+        if !self.fits(msg.0.uploader, msg.0.size) {
+            return Err(StoreError::QuotaExceeded);
+        }
+
+        self.uploads.insert(msg.0.id, msg.0);
+        Ok(())
+    }
+}
+
+impl Handler<Release> for UploadStore {
+    type Result = ();
+
+    fn handle(&mut self, msg: Release, _: &mut Context<Self>) {
+        if self.reserved.remove(&msg.id).is_none() {
+            return;
+        }
+        match std::fs::remove_file(part_path(&self.config.dir, msg.id)) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                log::warn!("cant remove unfinished upload {}: {}", msg.id, err);
+            }
+            _ => (),
+        }
+    }
+}
+
+impl Handler<Create> for UploadStore {
+    type Result = Result<u64, StoreError>;
+
+    fn handle(&mut self, msg: Create, _: &mut Context<Self>) -> Self::Result {
+        if !self.fits(msg.uploader, msg.length) {
+            return Err(StoreError::QuotaExceeded);
+        }
+
+        let id: u64 = rand::thread_rng().gen();
+        std::fs::File::create(part_path(&self.config.dir, id)).map_err(|err| {
+            log::error!("cant create upload {}: {}", id, err);
+            StoreError::Io
+        })?;
+
+        self.partials.insert(
+            id,
+            Partial {
+                id,
+                room: msg.room,
+                uploader: msg.uploader,
+                name: msg.name,
+                access: msg.access,
+                length: msg.length,
+                offset: 0,
+                metadata: msg.metadata,
+                hasher: Sha256::new(),
+                busy: false,
+                expires: Instant::now() + self.config.ttl,
+            },
+        );
+
+        Ok(id)
+    }
+}
+
+impl Handler<Status> for UploadStore {
+    type Result = MessageResult<Status>;
+
+    fn handle(&mut self, msg: Status, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.partials.get(&msg.id).cloned())
+    }
+}
+
+impl Handler<BeginPatch> for UploadStore {
+    type Result = Result<Sha256, StoreError>;
+
+    fn handle(&mut self, msg: BeginPatch, _: &mut Context<Self>) -> Self::Result {
+        let partial = self.partials.get_mut(&msg.id).ok_or(StoreError::NotFound)?;
+        if partial.busy || partial.offset != msg.offset {
+            return Err(StoreError::Conflict);
+        }
+
+        partial.busy = true;
+        Ok(partial.hasher.clone())
+    }
+}
+
+impl Handler<EndPatch> for UploadStore {
+    type Result = Result<Option<Upload>, StoreError>;
+
+    fn handle(&mut self, msg: EndPatch, _: &mut Context<Self>) -> Self::Result {
+        let partial = self.partials.get_mut(&msg.id).ok_or(StoreError::NotFound)?;
+        partial.busy = false;
+        partial.offset = msg.offset;
+        partial.hasher = msg.hasher;
+        partial.expires = Instant::now() + self.config.ttl;
+
+        if partial.offset < partial.length {
+            return Ok(None);
+        }
+
+        let Some(partial) = self.partials.remove(&msg.id) else {
+            return Err(StoreError::NotFound);
+        };
+        let key: u64 = rand::thread_rng().gen();
+        let upload = Upload {
+            id: partial.id,
+            room: partial.room,
+            uploader: partial.uploader,
+            name: partial.name,
+            size: partial.length,
+            hash: format!("sha256:{:x}", partial.hasher.finalize()),
+            access: partial.access,
+            link: link(partial.id, partial.access, key),
+            downloads_left: self.config.max_downloads.max(1),
+            key,
+            expires: Instant::now() + self.config.ttl,
+        };
+
+        let part = part_path(&self.config.dir, upload.id);
+        if let Err(err) = std::fs::rename(&part, upload.path(&self.config.dir)) {
+            log::error!("cant finish upload {}: {}", upload.id, err);
+            let _ = std::fs::remove_file(part);
+            return Err(StoreError::Io);
+        }
+
+        self.uploads.insert(upload.id, upload.clone());
+        Ok(Some(upload))
+    }
+}
+
+impl Handler<AbortPatch> for UploadStore {
+    type Result = ();
+
+    fn handle(&mut self, msg: AbortPatch, _: &mut Context<Self>) {
+        let Some(partial) = self.partials.get_mut(&msg.id) else {
+            return;
+        };
+        // the PATCH got to `EndPatch` after all
+        if !partial.busy || partial.offset != msg.offset {
+            return;
+        }
+
+        partial.busy = false;
+        if let Err(err) = std::fs::File::options()
+            .write(true)
+            .open(part_path(&self.config.dir, msg.id))
+            .and_then(|file| file.set_len(msg.offset))
+        {
+            log::error!("cant discard chunk of upload {}: {}", msg.id, err);
+        }
+    }
+}
+
+impl Handler<Terminate> for UploadStore {
+    type Result = bool;
+
+    fn handle(&mut self, msg: Terminate, _: &mut Context<Self>) -> bool {
+        if !self.partials.contains_key(&msg.id) {
+            return false;
+        }
+
+        self.remove_partial(msg.id);
+        true
+    }
+}
+
+impl Handler<Fetch> for UploadStore {
+    type Result = MessageResult<Fetch>;
+
+    fn handle(&mut self, msg: Fetch, _: &mut Context<Self>) -> Self::Result {
+        let Some(upload) = self.uploads.get_mut(&msg.id) else {
+            return MessageResult(None);
+        };
+        if upload.expires <= Instant::now() || upload.downloads_left == 0 {
+            self.remove(msg.id);
+            return MessageResult(None);
+        }
+
+        upload.downloads_left -= 1;
+        let upload = upload.clone();
+
+        // the file stays until the last download was served, the sweep deletes it then
+        if upload.downloads_left == 0 {
+            if let Some(last) = self.uploads.get_mut(&msg.id) {
+                last.expires = Instant::now() + SWEEP_INTERVAL;
+            }
+        }
+
+        MessageResult(Some(upload))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn store(dir: &std::path::Path) -> UploadStore {
+        UploadStore::new(UploadConfig {
+            dir: dir.to_owned(),
+            max_size: 100,
+            ttl: Duration::from_secs(60),
+            max_downloads: 1,
+            quota: 1000,
+            session_quota: 1000,
+        })
+    }
+
+    #[actix_web::test]
+    async fn patches_conflict_while_busy_or_at_another_offset() {
+        let dir = std::env::temp_dir().join(format!("peershare-uploads-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let store = store(&dir).start();
+
+        let create = Create {
+            room: 1,
+            uploader: 2,
+            name: "a.txt".to_owned(),
+            access: Access::Room,
+            length: 8,
+            metadata: String::new(),
+        };
+        let id = store.send(create).await.unwrap().unwrap();
+        let begin = |offset| store.send(BeginPatch { id, offset });
+
+        assert!(matches!(begin(4).await.unwrap(), Err(StoreError::Conflict)));
+        let hasher = begin(0).await.unwrap().unwrap();
+        assert!(matches!(begin(0).await.unwrap(), Err(StoreError::Conflict)));
+
+        // a PATCH that went away leaves the upload where it started
+        let mut part = std::fs::OpenOptions::new().append(true).open(part_path(&dir, id)).unwrap();
+        part.write_all(b"abc").unwrap();
+        store.send(AbortPatch { id, offset: 0 }).await.unwrap();
+        assert_eq!(std::fs::metadata(part_path(&dir, id)).unwrap().len(), 0);
+        begin(0).await.unwrap().unwrap();
+
+        part.write_all(b"abcd").unwrap();
+        let end = EndPatch { id, offset: 4, hasher };
+        assert!(store.send(end).await.unwrap().unwrap().is_none());
+        store.send(AbortPatch { id, offset: 0 }).await.unwrap();
+        assert!(matches!(begin(0).await.unwrap(), Err(StoreError::Conflict)));
+        begin(4).await.unwrap().unwrap();
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}